@@ -26,11 +26,13 @@ fn main() {
         //"nsq-vodafone-1.tngrm.io:4150", // nsqd address
         config, //configuration
         None, // optional secret for authentication
-        500, // rdy
+        500, // max_in_flight
         6, // max_attemps
         conn_receiver,
         info_sender,
         );
     c.spawn(8, MyReader{});
-    c.run();
+    if let Err(e) = c.run() {
+        println!("client stopped: {}", e);
+    }
 }
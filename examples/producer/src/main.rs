@@ -35,11 +35,13 @@ fn main() {
         //"nsq-vodafone-1.tngrm.io:4150", // nsqd address
         config, //configuration
         None,   // optional secret for authentication
-        500,    // rdy unused on Producer
+        500,    // max_in_flight unused on Producer
         6,      // max_attemps unused on Producer
         conn_receiver,
         info_sender,
     );
     c.spawn_producer(1, MyProducer {});
-    c.run();
+    if let Err(e) = c.run() {
+        println!("client stopped: {}", e);
+    }
 }
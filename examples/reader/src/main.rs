@@ -47,7 +47,7 @@ impl Handler<Msg> for MyReader {
     type Result = ();
     fn handle(&mut self, msg: Msg, ctx: &mut Self::Context) {
         println!("MyReader: {:?}", msg);
-        if let Ok(body) = String::from_utf8(msg.body) {
+        if let Ok(body) = std::str::from_utf8(&msg.body) {
             println!("utf8 msg: {}", body);
         }
         let id = msg.id;
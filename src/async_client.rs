@@ -0,0 +1,568 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::VecDeque;
+use std::future;
+use std::io;
+use std::pin::Pin;
+use std::task::{self, Poll};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures_core::Stream;
+use log::{debug, error};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+
+use crate::async_context::{ContextAsync, Request};
+use crate::codec::{decode_msg, encode_cmd, Frame, FrameDecoder, CLOSE_WAIT, HEARTBEAT};
+use crate::compression::Compression;
+use crate::config::{Config, NsqdConfig, TlsBackend};
+use crate::error::ConnError;
+use crate::msgs::{Msg, Nop, NsqCmd};
+use crate::reader::AsyncConsumer;
+use crate::state::{Handshake, Magic, Params, State, Waiting};
+
+/// Client running on a tokio runtime, a single nsqd connection is driven by a spawned task.
+///
+/// ```no-run
+/// use nsq_client::{AsyncClient, Config, Pub};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let client = AsyncClient::new("", "", "localhost:4150", Config::default(), None, 0);
+///     let (ctx, _msgs) = client.connect().await.unwrap();
+///     ctx.publish(Pub("test".to_owned(), b"hello".to_vec())).await.unwrap();
+/// }
+/// ```
+pub struct AsyncClient {
+    topic: String,
+    channel: String,
+    addr: String,
+    config: Config,
+    secret: Option<String>,
+    max_in_flight: u32,
+}
+
+impl AsyncClient {
+    pub fn new<S: Into<String>>(
+        topic: S,
+        channel: S,
+        addr: S,
+        config: Config,
+        secret: Option<S>,
+        max_in_flight: u32,
+    ) -> AsyncClient {
+        AsyncClient {
+            topic: topic.into(),
+            channel: channel.into(),
+            addr: addr.into(),
+            config,
+            secret: secret.map(Into::into),
+            max_in_flight,
+        }
+    }
+
+    /// Connect to nsqd, subscribing to the topic unless topic and channel are empty.
+    ///
+    /// The context publishes and sends commands on the connection, the stream yields
+    /// the messages received until the connection is closed or lost.
+    pub async fn connect(&self) -> Result<(ContextAsync, MsgStream), ConnError> {
+        let (transport, nsqd_config) = self.handshake().await?;
+        let (cmd_s, cmd_r) = mpsc::unbounded_channel();
+        let (msg_s, msg_r) = mpsc::unbounded_channel();
+        let (done_s, done_r) = oneshot::channel();
+        let conn = Connection {
+            addr: self.addr.clone(),
+            transport,
+            cmd_r,
+            msg_s,
+            msg_timeout: nsqd_config.msg_timeout,
+            read_timeout: read_timeout(&self.config),
+            pending: VecDeque::new(),
+        };
+        tokio::spawn(async move {
+            let addr = conn.addr.clone();
+            let res = conn.run().await;
+            if let Err(e) = &res {
+                error!("[{}] connection lost: {}", addr, e);
+            }
+            let _ = done_s.send(res);
+        });
+        let max_req_timeout = Duration::from_millis(self.config.max_req_timeout);
+        Ok((
+            ContextAsync::new(cmd_s, max_req_timeout),
+            MsgStream { msg_r, done_r },
+        ))
+    }
+
+    /// Connect and hand every message to a clone of the consumer, each in its own task.
+    ///
+    /// Returns once the connection is closed, use [connect](#method.connect) to keep a
+    /// handle able to close it.
+    pub async fn run<C: AsyncConsumer>(&self, consumer: C) -> Result<(), ConnError> {
+        let (ctx, mut msgs) = self.connect().await?;
+        while let Some(msg) = msgs.next().await {
+            let mut consumer = consumer.clone();
+            tokio::spawn(consumer.on_msg(msg, ctx.clone()));
+        }
+        msgs.closed().await
+    }
+
+    // MAGIC, IDENTIFY and the upgrades nsqd agreed to, then AUTH, SUB and RDY.
+    async fn handshake(&self) -> Result<(Transport, NsqdConfig), ConnError> {
+        let socket = TcpStream::connect(&self.addr).await?;
+        let mut transport = Transport::new(socket, &self.config);
+        let params = Params {
+            addr: self.addr.clone(),
+            config: self.config.clone(),
+            topic: self.topic.clone(),
+            channel: self.channel.clone(),
+            secret: self.secret.clone(),
+        };
+        let identify = Magic::new(params).identify(&mut transport.w_buf)?;
+        let mut handshake = Handshake::Waiting(Waiting::Identify(identify));
+        loop {
+            transport.flush().await?;
+            handshake = match handshake {
+                Handshake::Waiting(state) => {
+                    let resp = transport.response().await?;
+                    state.on_response(&resp, &mut transport.w_buf)?
+                }
+                Handshake::Tls(tls) => {
+                    let timeout = Duration::from_millis(self.config.tls_handshake_timeout);
+                    let server_name = tls.server_name();
+                    let upgrade = transport.tls(&self.config, &server_name);
+                    transport = time::timeout(timeout, upgrade).await.map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "tls handshake timed out")
+                    })??;
+                    Handshake::Waiting(tls.upgraded())
+                }
+                Handshake::Compress(compress) => {
+                    let (compression, state) = compress.enable();
+                    transport.compress(compression)?;
+                    Handshake::Waiting(state)
+                }
+                Handshake::Rdy(rdy) => {
+                    Handshake::Done(rdy.rdy(self.max_in_flight, &mut transport.w_buf))
+                }
+                Handshake::Done(done) => return Ok((transport, done.nsqd_config().clone())),
+            };
+        }
+    }
+}
+
+/// Messages received by an [AsyncClient](struct.AsyncClient.html) connection.
+pub struct MsgStream {
+    msg_r: mpsc::UnboundedReceiver<Msg>,
+    done_r: oneshot::Receiver<Result<(), ConnError>>,
+}
+
+impl MsgStream {
+    /// Next message, None once the connection is closed.
+    pub async fn next(&mut self) -> Option<Msg> {
+        self.msg_r.recv().await
+    }
+
+    /// Wait for the connection to end, Ok if it was closed through
+    /// [ContextAsync::close](struct.ContextAsync.html#method.close).
+    pub async fn closed(self) -> Result<(), ConnError> {
+        self.done_r.await.unwrap_or(Ok(()))
+    }
+}
+
+impl Stream for MsgStream {
+    type Item = Msg;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<Msg>> {
+        self.get_mut().msg_r.poll_recv(cx)
+    }
+}
+
+// socket, tls stream or whatever nsqd is reached through.
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+// nsqd stream with its buffers, upgraded in place by tls and compression.
+struct Transport {
+    stream: Box<dyn AsyncStream>,
+    decoder: FrameDecoder,
+    w_buf: BytesMut,
+    buf: Vec<u8>,
+    compression: Option<Compression>,
+}
+
+impl Transport {
+    fn new(socket: TcpStream, config: &Config) -> Transport {
+        Transport {
+            stream: Box::new(socket),
+            decoder: FrameDecoder::new().max_msg_size(config.max_msg_size as usize),
+            w_buf: BytesMut::new(),
+            buf: vec![0; config.output_buffer_size as usize],
+            compression: None,
+        }
+    }
+
+    fn write_cmd<C: NsqCmd>(&mut self, cmd: C) {
+        let cmd = cmd.as_cmd();
+        debug!("{:?}", cmd);
+        encode_cmd(&mut self.w_buf, cmd);
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.w_buf.is_empty() {
+            return Ok(());
+        }
+        let buf = self.w_buf.split_off(0);
+        match self.compression.as_mut() {
+            Some(compression) => {
+                let compressed = compression.compress(&buf)?;
+                self.stream.write_all(&compressed).await?;
+            }
+            None => self.stream.write_all(&buf).await?,
+        }
+        self.stream.flush().await
+    }
+
+    // read what the stream has to the read buffer, 0 when nsqd closed the connection.
+    async fn read(&mut self) -> io::Result<usize> {
+        let n = self.stream.read(&mut self.buf).await?;
+        match self.compression.as_mut() {
+            Some(compression) => {
+                compression.decompress(&self.buf[..n], self.decoder.buffer_mut())?;
+            }
+            None => self.decoder.extend(&self.buf[..n]),
+        }
+        Ok(n)
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, ConnError> {
+        loop {
+            if let Some(frame) = self.decoder.decode()? {
+                return Ok(frame);
+            }
+            if self.read().await? == 0 {
+                return Err(eof());
+            }
+        }
+    }
+
+    // response to the last command, heartbeats are answered meanwhile.
+    async fn response(&mut self) -> Result<String, ConnError> {
+        loop {
+            match self.read_frame().await? {
+                Frame::Response(resp) if resp == HEARTBEAT => {
+                    self.write_cmd(Nop);
+                    self.flush().await?;
+                }
+                Frame::Response(resp) => return Ok(resp),
+                Frame::Error(e) => return Err(ConnError::from_frame(&e)),
+                Frame::Message(_) => {
+                    return Err(ConnError::Error(
+                        "unexpected message waiting for a response".to_owned(),
+                    ))
+                }
+            }
+        }
+    }
+
+    // nsqd compresses right after its response, the bytes read past it are compressed too.
+    fn compress(&mut self, mut compression: Compression) -> io::Result<()> {
+        let leftover = self.decoder.take();
+        compression.decompress(&leftover, self.decoder.buffer_mut())?;
+        self.compression = Some(compression);
+        Ok(())
+    }
+
+    async fn tls(self, config: &Config, server_name: &str) -> Result<Transport, ConnError> {
+        let stream = match config.tls_backend {
+            TlsBackend::NativeTls => native_tls(config, server_name, self.stream).await?,
+            TlsBackend::Rustls => rustls(config, server_name, self.stream).await?,
+        };
+        Ok(Transport { stream, ..self })
+    }
+}
+
+// task owning the connection once the handshake is done.
+struct Connection {
+    addr: String,
+    transport: Transport,
+    cmd_r: mpsc::UnboundedReceiver<Request>,
+    msg_s: mpsc::UnboundedSender<Msg>,
+    msg_timeout: u64,
+    read_timeout: Option<Duration>,
+    // publishes waiting for nsqd to answer, responses come in order.
+    pending: VecDeque<oneshot::Sender<Result<(), ConnError>>>,
+}
+
+impl Connection {
+    async fn run(mut self) -> Result<(), ConnError> {
+        let mut deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            while let Some(frame) = self.transport.decoder.decode()? {
+                if !self.on_frame(frame).await? {
+                    return Ok(());
+                }
+            }
+            tokio::select! {
+                res = self.transport.read() => {
+                    if res? == 0 {
+                        return Err(eof());
+                    }
+                    deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+                }
+                req = self.cmd_r.recv() => match req {
+                    Some(Request::Cmd(cmd)) => {
+                        self.transport.write_cmd(cmd);
+                        self.transport.flush().await?;
+                    }
+                    Some(Request::Publish(cmd, done)) => {
+                        self.pending.push_back(done);
+                        self.transport.write_cmd(cmd);
+                        self.transport.flush().await?;
+                    }
+                    // every context is gone, nothing could finish the messages anymore.
+                    Some(Request::Close) | None => {
+                        let _ = self.transport.stream.shutdown().await;
+                        return Ok(());
+                    }
+                },
+                _ = expired(deadline) => {
+                    return Err(ConnError::IoError(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "heartbeat not received from nsqd",
+                    )));
+                }
+            }
+        }
+    }
+
+    // Returns false when nsqd acknowledged the close of the connection.
+    async fn on_frame(&mut self, frame: Frame) -> Result<bool, ConnError> {
+        match frame {
+            Frame::Message(mut frame) => {
+                let (timestamp, attemps, id, body) = decode_msg(&mut frame);
+                let msg = Msg {
+                    timeout: self.msg_timeout,
+                    timestamp,
+                    attemps,
+                    id,
+                    body,
+                };
+                if self.msg_s.send(msg).is_err() {
+                    debug!("[{}] message stream dropped", self.addr);
+                }
+            }
+            Frame::Response(resp) => {
+                if resp == HEARTBEAT {
+                    self.transport.write_cmd(Nop);
+                    self.transport.flush().await?;
+                } else if resp == CLOSE_WAIT {
+                    return Ok(false);
+                } else if let Some(done) = self.pending.pop_front() {
+                    let _ = done.send(Ok(()));
+                } else {
+                    debug!("[{}] response: {}", self.addr, resp);
+                }
+            }
+            Frame::Error(e) => {
+                let err = ConnError::from_frame(&e);
+                if !err.is_fatal() {
+                    error!("[{}] {}", self.addr, err);
+                } else if let Some(done) = self.pending.pop_front() {
+                    // nsqd closes the connection right after.
+                    let _ = done.send(Err(err));
+                } else {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "async-native-tls")]
+async fn native_tls(
+    config: &Config,
+    server_name: &str,
+    stream: Box<dyn AsyncStream>,
+) -> Result<Box<dyn AsyncStream>, ConnError> {
+    let connector = tokio_native_tls::TlsConnector::from(crate::tls::native_connector(config)?);
+    Ok(Box::new(connector.connect(server_name, stream).await?))
+}
+
+#[cfg(not(feature = "async-native-tls"))]
+async fn native_tls(
+    _config: &Config,
+    _server_name: &str,
+    _stream: Box<dyn AsyncStream>,
+) -> Result<Box<dyn AsyncStream>, ConnError> {
+    Err(ConnError::Error(
+        "native-tls support not compiled for the async client, enable the async-native-tls feature"
+            .to_owned(),
+    ))
+}
+
+#[cfg(feature = "async-rustls")]
+async fn rustls(
+    config: &Config,
+    server_name: &str,
+    stream: Box<dyn AsyncStream>,
+) -> Result<Box<dyn AsyncStream>, ConnError> {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    let tls_config = crate::tls::rustls_config(config)?;
+    let name = rustls::ServerName::try_from(server_name)
+        .map_err(|e| ConnError::Error(format!("invalid tls server name {}: {}", server_name, e)))?;
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
+    let stream = connector
+        .connect(name, stream)
+        .await
+        .map_err(crate::tls::rustls_error)?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(feature = "async-rustls"))]
+async fn rustls(
+    _config: &Config,
+    _server_name: &str,
+    _stream: Box<dyn AsyncStream>,
+) -> Result<Box<dyn AsyncStream>, ConnError> {
+    Err(ConnError::Error(
+        "rustls support not compiled for the async client, enable the async-rustls feature"
+            .to_owned(),
+    ))
+}
+
+// maximum time without reading anything from nsqd before the connection is considered lost.
+fn read_timeout(config: &Config) -> Option<Duration> {
+    if config.heartbeat_interval <= 0 {
+        return None;
+    }
+    Some(Duration::from_millis(config.heartbeat_interval as u64 * 2))
+}
+
+async fn expired(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+fn eof() -> ConnError {
+    ConnError::IoError(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed by nsqd",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{frame, read_body, NSQD_CONFIG};
+    use crate::msgs::{Fin, Pub};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // nsqd sending a heartbeat and a message once RDY is received, the first PUB is
+    // accepted and the second refused. Returns the commands received.
+    fn nsqd() -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic).unwrap();
+            assert_eq!(&magic, b"  V2");
+            let mut cmds = Vec::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return cmds;
+                }
+                let cmd = line.trim_end().to_owned();
+                if cmd == "IDENTIFY" {
+                    read_body(&mut reader);
+                    stream.write_all(&frame(0, NSQD_CONFIG.as_bytes())).unwrap();
+                } else if cmd.starts_with("SUB") {
+                    stream.write_all(&frame(0, b"OK")).unwrap();
+                } else if cmd.starts_with("RDY") {
+                    let mut msg = Vec::new();
+                    msg.extend_from_slice(&0i64.to_be_bytes());
+                    msg.extend_from_slice(&1u16.to_be_bytes());
+                    msg.extend_from_slice(b"0000000000000001");
+                    msg.extend_from_slice(b"body");
+                    let mut buf = frame(0, HEARTBEAT.as_bytes());
+                    buf.extend_from_slice(&frame(2, &msg));
+                    stream.write_all(&buf).unwrap();
+                } else if cmd.starts_with("PUB") {
+                    if read_body(&mut reader) == b"ok" {
+                        stream.write_all(&frame(0, b"OK")).unwrap();
+                    } else {
+                        stream.write_all(&frame(1, b"E_BAD_MESSAGE bad")).unwrap();
+                    }
+                }
+                cmds.push(cmd);
+            }
+        });
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn consume_and_publish() {
+        let (addr, handle) = nsqd();
+        let client = AsyncClient::new("t", "c", &addr, Config::default(), None, 10);
+        let (ctx, mut msgs) = client.connect().await.unwrap();
+        let msg = msgs.next().await.unwrap();
+        assert_eq!(&msg.body[..], b"body");
+        assert_eq!(msg.timeout, 60000);
+        ctx.send(Fin(msg.id));
+        ctx.publish(Pub("t".to_owned(), b"ok".to_vec()))
+            .await
+            .unwrap();
+        match ctx.publish(Pub("t".to_owned(), b"ko".to_vec())).await {
+            Err(ConnError::BadMessage(desc)) => assert_eq!(desc, "bad"),
+            res => panic!("unexpected publish result {:?}", res),
+        }
+        ctx.close();
+        assert!(msgs.next().await.is_none());
+        msgs.closed().await.unwrap();
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                "IDENTIFY",
+                "SUB t c",
+                "RDY 10",
+                "NOP",
+                "FIN 0000000000000001",
+                "PUB t",
+                "PUB t"
+            ]
+        );
+    }
+}
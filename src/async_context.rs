@@ -1,23 +1,93 @@
-use crossbeam::channel::Sender;
-use std::sync::Mutex;
-//use crate::client::{CmdChannel};
-use crate::msgs::{Cmd, NsqCmd};
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
 
+use crate::error::{ConnError, DelayError};
+use crate::msgs::{check_delay, Cmd, MessageId, NsqCmd, Requeue};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+// what handlers ask to the task owning the connection.
+#[derive(Debug)]
+pub(crate) enum Request {
+    Cmd(Cmd),
+    Publish(Cmd, oneshot::Sender<Result<(), ConnError>>),
+    Close,
+}
+
+/// Handle to an [AsyncClient](struct.AsyncClient.html) connection, cheap to clone.
+#[derive(Clone, Debug)]
 pub struct ContextAsync {
-    cmd: Sender<Cmd>,
-    sentinel: Mutex<Sender<()>>,
+    cmd: mpsc::UnboundedSender<Request>,
+    max_req_timeout: Duration,
 }
 
 impl ContextAsync {
-    pub fn new(cmd: Sender<Cmd>, sentinel: Sender<()>) -> ContextAsync {
+    pub(crate) fn new(
+        cmd: mpsc::UnboundedSender<Request>,
+        max_req_timeout: Duration,
+    ) -> ContextAsync {
         ContextAsync {
             cmd,
-            sentinel: Mutex::new(sentinel),
+            max_req_timeout,
         }
     }
 
-    pub fn send<C: NsqCmd>(&mut self, cmd: C) {
-        let _ = self.cmd.send(cmd.as_cmd());
-        let _ = self.sentinel.lock().unwrap().send(());
+    /// Send a command to nsqd without waiting for an answer (ex. FIN, REQ, TOUCH).
+    pub fn send<C: NsqCmd>(&self, cmd: C) {
+        let _ = self.cmd.send(Request::Cmd(cmd.as_cmd()));
     }
+
+    /// Requeue a message delivered again after delay (REQ).
+    ///
+    /// Delays longer than [max_req_timeout](struct.Config.html#structfield.max_req_timeout)
+    /// are refused, nothing is sent.
+    pub fn requeue(&self, id: MessageId, delay: Duration) -> Result<(), DelayError> {
+        let req = Requeue(id, delay);
+        check_delay(&req, self.max_req_timeout)?;
+        self.send(req);
+        Ok(())
+    }
+
+    /// Publish with a [Pub](struct.Pub.html), [Mpub](struct.Mpub.html) or
+    /// [Dpub](struct.Dpub.html), resolves once nsqd answered.
+    ///
+    /// A DPUB delay longer than [max_req_timeout](struct.Config.html#structfield.max_req_timeout)
+    /// is refused with [ConnError::Delay](enum.ConnError.html#variant.Delay).
+    pub async fn publish<C: NsqCmd>(&self, cmd: C) -> Result<(), ConnError> {
+        check_delay(&cmd, self.max_req_timeout)?;
+        let (done_s, done_r) = oneshot::channel();
+        self.cmd
+            .send(Request::Publish(cmd.as_cmd(), done_s))
+            .map_err(|_| closed())?;
+        done_r.await.map_err(|_| closed())?
+    }
+
+    /// Close the connection, the message stream ends once the buffered messages are read.
+    pub fn close(&self) {
+        let _ = self.cmd.send(Request::Close);
+    }
+}
+
+fn closed() -> ConnError {
+    ConnError::Error("connection closed".to_owned())
 }
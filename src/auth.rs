@@ -21,11 +21,16 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use serde_derive::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 
+/// Response sent by nsqd to a successful AUTH command.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AuthResp {
-    identity: String,
-    identity_url: String,
-    permission_count: u32,
+    /// Identity the secret was authorized as.
+    pub identity: String,
+    /// Optional url describing the identity.
+    #[serde(default)]
+    pub identity_url: String,
+    /// Number of permissions granted to the identity.
+    pub permission_count: u32,
 }
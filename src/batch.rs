@@ -0,0 +1,300 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+
+use crate::config::Config;
+use crate::error::{ConnError, PubError};
+use crate::msgs::Mpub;
+use crate::publisher::Publisher;
+
+type Reply = Sender<Result<(), PubError>>;
+
+// single message waiting for its batch.
+struct Batched {
+    topic: String,
+    body: Vec<u8>,
+    reply: Reply,
+}
+
+// MPUB written, waiting for the response of nsqd.
+struct InFlight {
+    resp: Receiver<Result<(), PubError>>,
+    replies: Vec<Reply>,
+}
+
+struct Batch {
+    bodies: Vec<Vec<u8>>,
+    replies: Vec<Reply>,
+    // size of the MPUB body: message count then every message with its size.
+    size: usize,
+    deadline: Instant,
+}
+
+impl Batch {
+    fn new(linger: Duration) -> Batch {
+        Batch {
+            bodies: Vec::new(),
+            replies: Vec::new(),
+            size: 4,
+            deadline: Instant::now() + linger,
+        }
+    }
+}
+
+/// Publish single messages grouped per topic in MPUB.
+///
+/// A batch is published once it holds
+/// [batch_max_count](struct.Config.html#structfield.batch_max_count) messages,
+/// [batch_max_size](struct.Config.html#structfield.batch_max_size) bytes or waited
+/// [batch_linger](struct.Config.html#structfield.batch_linger) milliseconds, whichever
+/// comes first. It never grows past
+/// [max_body_size](struct.Config.html#structfield.max_body_size).
+///
+/// Clones share the same batches, the background thread stops once they are all dropped.
+/// ```no-run
+/// use nsq_client::{Config, Publisher};
+///
+/// fn main() {
+///     let config = Config::new().batch(500, 1048576, 50);
+///     let batcher = Publisher::connect("127.0.0.1:4150", config, None)
+///         .unwrap()
+///         .batcher();
+///     // returns once the MPUB holding the message is acknowledged.
+///     batcher.publish("topic", b"hello".to_vec()).unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Batcher {
+    s: Sender<Batched>,
+}
+
+impl Batcher {
+    pub(crate) fn new(publisher: Publisher, config: &Config) -> Batcher {
+        let (s, r) = channel::unbounded();
+        let (in_flight_s, in_flight_r) = channel::unbounded();
+        let batching = Batching {
+            publisher,
+            batches: HashMap::new(),
+            in_flight: in_flight_s,
+            max_count: config.batch_max_count.max(1),
+            max_size: config.batch_max_size,
+            linger: Duration::from_millis(config.batch_linger),
+            max_body_size: config.max_body_size,
+        };
+        thread::spawn(move || batching.run(r));
+        // responses come in the order the MPUB are written.
+        thread::spawn(move || {
+            for in_flight in in_flight_r.iter() {
+                let res = in_flight.resp.recv().unwrap_or(Err(PubError::Closed));
+                for reply in in_flight.replies {
+                    let _ = reply.send(share(&res));
+                }
+            }
+        });
+        Batcher { s }
+    }
+
+    /// Add a message to the batch of topic and wait for the response to its MPUB.
+    pub fn publish<T: Into<String>>(&self, topic: T, body: Vec<u8>) -> Result<(), PubError> {
+        let (reply, reply_r) = channel::bounded(1);
+        let batched = Batched {
+            topic: topic.into(),
+            body,
+            reply,
+        };
+        self.s.send(batched).map_err(|_| PubError::Closed)?;
+        reply_r.recv().unwrap_or(Err(PubError::Closed))
+    }
+}
+
+struct Batching {
+    publisher: Publisher,
+    batches: HashMap<String, Batch>,
+    in_flight: Sender<InFlight>,
+    max_count: usize,
+    max_size: usize,
+    linger: Duration,
+    max_body_size: usize,
+}
+
+impl Batching {
+    fn run(mut self, r: Receiver<Batched>) {
+        loop {
+            let next_deadline = self.batches.values().map(|batch| batch.deadline).min();
+            let res = match next_deadline {
+                Some(deadline) => {
+                    r.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => r.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match res {
+                Ok(batched) => self.add(batched),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    let topics: Vec<String> = self.batches.keys().cloned().collect();
+                    for topic in topics {
+                        self.flush(&topic);
+                    }
+                    return;
+                }
+            }
+            let now = Instant::now();
+            let expired: Vec<String> = self
+                .batches
+                .iter()
+                .filter(|(_, batch)| batch.deadline <= now)
+                .map(|(topic, _)| topic.clone())
+                .collect();
+            for topic in expired {
+                self.flush(&topic);
+            }
+        }
+    }
+
+    fn add(&mut self, batched: Batched) {
+        let size = 4 + batched.body.len();
+        if 4 + size > self.max_body_size {
+            let _ = batched
+                .reply
+                .send(Err(PubError::BodyTooLarge(batched.body.len())));
+            return;
+        }
+        let full = match self.batches.get(&batched.topic) {
+            Some(batch) => batch.size + size > self.max_body_size,
+            None => false,
+        };
+        if full {
+            self.flush(&batched.topic);
+        }
+        let linger = self.linger;
+        let batch = self
+            .batches
+            .entry(batched.topic.clone())
+            .or_insert_with(|| Batch::new(linger));
+        batch.bodies.push(batched.body);
+        batch.replies.push(batched.reply);
+        batch.size += size;
+        if batch.bodies.len() >= self.max_count || batch.size >= self.max_size {
+            self.flush(&batched.topic);
+        }
+    }
+
+    fn flush(&mut self, topic: &str) {
+        let batch = match self.batches.remove(topic) {
+            Some(batch) => batch,
+            None => return,
+        };
+        let resp = self.publisher.queue(Mpub(topic.to_owned(), batch.bodies));
+        let _ = self.in_flight.send(InFlight {
+            resp,
+            replies: batch.replies,
+        });
+    }
+}
+
+// every message of the MPUB gets the same result.
+fn share(res: &Result<(), PubError>) -> Result<(), PubError> {
+    match res {
+        Ok(()) => Ok(()),
+        // publish errors are built from an nsqd error frame.
+        Err(PubError::Nsqd(e)) => Err(PubError::Nsqd(
+            e.clone_frame()
+                .unwrap_or_else(|| ConnError::Error(e.to_string())),
+        )),
+        Err(PubError::ConnectionLost) => Err(PubError::ConnectionLost),
+        Err(PubError::Closed) => Err(PubError::Closed),
+        Err(PubError::BodyTooLarge(size)) => Err(PubError::BodyTooLarge(*size)),
+        Err(PubError::Delay(e)) => Err(PubError::Delay(*e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publisher::tests::nsqd;
+
+    fn publish_all(
+        batcher: &Batcher,
+        topic: &str,
+        count: usize,
+        body: &[u8],
+    ) -> Vec<Result<(), PubError>> {
+        let threads: Vec<_> = (0..count)
+            .map(|_| {
+                let batcher = batcher.clone();
+                let topic = topic.to_owned();
+                let body = body.to_vec();
+                thread::spawn(move || batcher.publish(topic, body))
+            })
+            .collect();
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn thresholds() {
+        let (addr, handle) = nsqd(1, |_| None);
+        let config = Config::new().batch(5, 65536, 100).max_body_size(44);
+        let publisher = Publisher::connect(addr, config, None).unwrap();
+        let batcher = publisher.batcher();
+        let res = publish_all(&batcher, "count", 10, b"m");
+        assert!(res.iter().all(Result::is_ok));
+        // two messages fill the body, the third one goes in the next MPUB.
+        let res = publish_all(&batcher, "size", 3, b"0123456789abcdef");
+        assert!(res.iter().all(Result::is_ok));
+        match batcher.publish("size", vec![0; 37]) {
+            Err(PubError::BodyTooLarge(37)) => {}
+            res => panic!("unexpected publish result {:?}", res),
+        }
+        drop(publisher);
+        drop(batcher);
+        let cmds = handle.join().unwrap();
+        assert_eq!(cmds.iter().filter(|cmd| *cmd == "MPUB count").count(), 2);
+        assert_eq!(cmds.iter().filter(|cmd| *cmd == "MPUB size").count(), 2);
+    }
+
+    #[test]
+    fn linger_and_refused() {
+        let (addr, handle) = nsqd(1, |cmd| {
+            if cmd == "MPUB refused" {
+                return Some("E_MPUB_FAILED refused");
+            }
+            None
+        });
+        let config = Config::new().batch(100, 65536, 10);
+        let batcher = Publisher::connect(addr, config, None).unwrap().batcher();
+        batcher.publish("linger", b"msg".to_vec()).unwrap();
+        for res in publish_all(&batcher, "refused", 2, b"msg") {
+            match res {
+                Err(PubError::Nsqd(ConnError::MpubFailed(desc))) => assert_eq!(desc, "refused"),
+                res => panic!("unexpected publish result {:?}", res),
+            }
+        }
+        drop(batcher);
+        assert_eq!(handle.join().unwrap(), ["MPUB linger", "MPUB refused"]);
+    }
+}
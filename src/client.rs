@@ -1,34 +1,84 @@
-use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::net::Shutdown;
-use std::process;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
-use crossbeam::channel::{self, Receiver, Sender};
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use chrono::Utc;
+use crossbeam::channel::{self, select, Receiver, RecvTimeoutError, Sender};
 use log::{debug, error, info, warn};
-use native_tls::{HandshakeError, TlsConnector, TlsStream};
 
-use mio::{Events, Poll, PollOpt, Ready, Registration, Token};
-use serde_json;
+use mio::net::TcpStream;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 
-use crate::codec::decode_msg;
-use crate::config::{Config, NsqdConfig};
-use crate::conn::{connect, Conn, State, CONNECTION};
-use crate::msgs::{BytesMsg, Cmd, ConnInfo, ConnMsg, ConnMsgInfo, Msg, Nop, NsqCmd};
+use crate::codec::{decode_msg, Response, CLOSE_WAIT};
+use crate::config::Config;
+use crate::conn::{connect, Conn, CONNECTION};
+use crate::dead_letter::DeadLetter;
+use crate::error::{ConnError, DelayError, PubError};
+use crate::lookup::{lookup, LOOKUP_TIMEOUT};
+use crate::msgs::{
+    check_delay, AuthInfo, BytesMsg, Cls, Cmd, ConnInfo, ConnMsg, ConnMsgInfo, Fin, MessageId, Msg,
+    Nop, NsqCmd, ReconnectInfo, Requeue, Touch,
+};
 use crate::producer::Producer;
-use crate::reader::Consumer;
+use crate::publisher::PubRequest;
+use crate::rdy::{RdyBalancer, RDY_REDISTRIBUTE_INTERVAL};
+use crate::reader::{deliver, Consumer, Disposer, Disposition, Handler};
+use crate::state::{Done, Handshake, Magic, Params, State, Waiting};
+use crate::tls::{self, TlsStream};
 
 use bytes::BytesMut;
-#[cfg(target_os = "windows")]
 use std::io::{Read, Write};
 
 const CLIENT_TOKEN: Token = Token(4589);
 const CMD_TOKEN: Token = Token(3290);
 
-lazy_static! {
-    static ref CONNECTED: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
+// Connection state of a client, shared by its sessions, handlers and handles.
+#[derive(Debug, Default)]
+pub(crate) struct ClientState {
+    closed: AtomicBool,
+    connections: AtomicUsize,
+}
+
+/// Handle to query and close a [Client](struct.Client.html) from other threads,
+/// given by [Client::handle](struct.Client.html#method.handle).
+#[derive(Clone, Debug)]
+pub struct ClientHandle {
+    state: Arc<ClientState>,
+    close_s: Sender<ConnMsg>,
+}
+
+impl ClientHandle {
+    /// Close the connections of the client and stop its handlers, as
+    /// [ConnMsg::Close](enum.ConnMsg.html#variant.Close) does.
+    pub fn close(&self) {
+        let _ = self.close_s.send(ConnMsg::Close);
+    }
+
+    /// Finish the messages in flight then close the client, as
+    /// [ConnMsg::Drain](enum.ConnMsg.html#variant.Drain) does.
+    pub fn drain(&self) {
+        let _ = self.close_s.send(ConnMsg::Drain);
+    }
+
+    /// The client stopped, closed or after a connection failed for good.
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::SeqCst)
+    }
+
+    /// At least one connection to nsqd is started.
+    pub fn is_connected(&self) -> bool {
+        self.connections() > 0
+    }
+
+    /// Number of nsqd connections started.
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -51,12 +101,103 @@ impl MsgChannel {
     }
 }
 
-pub(crate) struct Sentinel(pub Sender<()>, Receiver<()>);
+// Bytes a connection couldn't write to nsqd yet.
+#[derive(Debug, Default)]
+struct Backlog {
+    bytes: Mutex<usize>,
+    drained: Condvar,
+}
 
-impl Sentinel {
-    fn new() -> Sentinel {
-        let (s, r) = channel::unbounded();
-        Sentinel(s, r)
+/// Way back from a handler to the connection which delivered the message.
+#[derive(Clone, Debug)]
+pub struct Route {
+    cmd_s: Sender<Cmd>,
+    waker: SetReadiness,
+    backlog: Arc<Backlog>,
+    // senders wait while the backlog is above it, 0 for no limit.
+    high_water_mark: usize,
+}
+
+impl Route {
+    pub(crate) fn new(cmd_s: Sender<Cmd>, waker: SetReadiness) -> Route {
+        Route {
+            cmd_s,
+            waker,
+            backlog: Arc::new(Backlog::default()),
+            high_water_mark: 0,
+        }
+    }
+
+    pub(crate) fn high_water_mark(mut self, bytes: usize) -> Route {
+        self.high_water_mark = bytes;
+        self
+    }
+
+    fn send(&self, cmd: Cmd) {
+        self.wait_backlog();
+        let _ = self.cmd_s.send(cmd);
+        self.wake();
+    }
+
+    // wait for the connection to write its backlog down to the high-water mark.
+    fn wait_backlog(&self) {
+        if self.high_water_mark == 0 {
+            return;
+        }
+        let mut bytes = self.backlog.bytes.lock().unwrap();
+        while *bytes > self.high_water_mark {
+            bytes = self.backlog.drained.wait(bytes).unwrap();
+        }
+    }
+
+    // bytes the connection has left to write.
+    pub(crate) fn set_backlog(&self, bytes: usize) {
+        *self.backlog.bytes.lock().unwrap() = bytes;
+        if bytes <= self.high_water_mark {
+            self.backlog.drained.notify_all();
+        }
+    }
+
+    // wake the connection up to write the commands queued.
+    pub(crate) fn wake(&self) {
+        if let Err(e) = self.waker.set_readiness(Ready::writable()) {
+            error!("error on handles waker: {}", e);
+        }
+    }
+}
+
+// session thread running in lookup mode.
+type SessionHandle = (SessionCloser, JoinHandle<Result<(), ConnError>>);
+
+// How a session is asked to close its connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Closing {
+    Now,
+    // RDY 0 and CLS, then close once the messages in flight are done.
+    Drain,
+}
+
+// Handle used to ask a running session to close its connection.
+#[derive(Clone)]
+pub(crate) struct SessionCloser {
+    s: Sender<Closing>,
+    waker: SetReadiness,
+}
+
+impl SessionCloser {
+    pub(crate) fn close(&self) {
+        self.send(Closing::Now);
+    }
+
+    pub(crate) fn drain(&self) {
+        self.send(Closing::Drain);
+    }
+
+    fn send(&self, closing: Closing) {
+        let _ = self.s.send(closing);
+        if let Err(e) = self.waker.set_readiness(Ready::readable()) {
+            error!("error on cmd waker: {}", e);
+        }
     }
 }
 
@@ -64,616 +205,424 @@ pub struct Client<S>
 where
     S: Into<String> + Clone,
 {
-    rdy: u32,
+    balancer: Arc<RdyBalancer>,
     max_attemps: u16,
     channel: String,
     topic: String,
     addr: String,
+    lookupd: Vec<String>,
     config: Config,
     secret: Option<S>,
     msg_channel: MsgChannel,
     cmd_channel: CmdChannel,
-    sentinel: Sentinel,
+    route: Route,
+    cmd_handler: Option<Registration>,
     in_cmd: Receiver<ConnMsg>,
     out_info: Sender<ConnMsgInfo>,
-    connected_s: Sender<bool>,
-    connected_r: Receiver<bool>,
     msg_timeout: u64,
+    dead_letter: Option<DeadLetter>,
+    state: Arc<ClientState>,
+    // messages of the handles.
+    close: (Sender<ConnMsg>, Receiver<ConnMsg>),
 }
 
 impl<S> Client<S>
 where
     S: Into<String> + Clone,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         topic: S,
         channel: S,
         addr: S,
         config: Config,
         secret: Option<S>,
-        rdy: u32,
+        max_in_flight: u32,
         max_attemps: u16,
         in_cmd: Receiver<ConnMsg>,
         out_info: Sender<ConnMsgInfo>,
     ) -> Client<S> {
-        let (s, r): (Sender<bool>, Receiver<bool>) = channel::unbounded();
+        let cmd_channel = CmdChannel::new();
+        let (cmd_handler, waker) = Registration::new2();
+        let balancer = RdyBalancer::new(max_in_flight).backoff(
+            Duration::from_millis(config.backoff_multiplier),
+            Duration::from_millis(config.max_backoff_duration),
+            out_info.clone(),
+        );
+        let route = Route::new(cmd_channel.0.clone(), waker)
+            .high_water_mark(config.write_high_water_mark as usize);
         Client {
             topic: topic.into(),
             channel: channel.into(),
             addr: addr.into(),
+            lookupd: Vec::new(),
             config,
-            rdy,
+            balancer: Arc::new(balancer),
             secret,
             max_attemps,
             msg_channel: MsgChannel::new(),
-            cmd_channel: CmdChannel::new(),
-            sentinel: Sentinel::new(),
+            route,
+            cmd_channel,
+            cmd_handler: Some(cmd_handler),
             in_cmd,
             out_info,
-            connected_s: s,
-            connected_r: r,
             msg_timeout: 0,
+            dead_letter: None,
+            state: Arc::new(ClientState::default()),
+            close: channel::unbounded(),
         }
     }
 
-    pub fn run(&mut self) -> io::Result<()> {
-        let (handler, set_readiness) = Registration::new2();
-        let r_sentinel = self.sentinel.1.clone();
-        let CONN1 = CONNECTED.clone();
-        thread::spawn(move || {
-            let lock = &*CONN1;
-            loop {
-                if let Ok(_ok) = r_sentinel.recv() {
-                    if let Err(e) = set_readiness.set_readiness(Ready::writable()) {
-                        error!("error on handles waker: {}", e);
+    /// Discover the nsqd nodes producing the topic through a nsqlookupd http address.
+    ///
+    /// When at least one nsqlookupd is added the `addr` given to `new` is ignored,
+    /// nsqlookupd is polled every
+    /// [lookupd_poll_interval](struct.Config.html#structfield.lookupd_poll_interval)
+    /// and a connection is opened to every nsqd found.
+    pub fn add_lookupd(&mut self, addr: S) {
+        self.lookupd.push(addr.into());
+    }
+
+    /// Publish the messages a [Handler](trait.Handler.html) fails on for their last
+    /// allowed attempt, or received past max_attemps, to a dead-letter topic.
+    ///
+    /// The message is finished once nsqd acknowledged the publish, requeued otherwise.
+    /// Only the handlers spawned after it are concerned.
+    pub fn dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter = Some(dead_letter.source(&self.topic, &self.channel));
+    }
+
+    /// Handle to follow the connections and close the client once it runs.
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle {
+            state: self.state.clone(),
+            close_s: self.close.0.clone(),
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), ConnError> {
+        if !self.lookupd.is_empty() {
+            return self.discover();
+        }
+        let cmd_handler = self
+            .cmd_handler
+            .take()
+            .ok_or_else(|| ConnError::Error("client is already running".to_owned()))?;
+        let (mut session, closer) = self.session(
+            self.addr.clone(),
+            self.cmd_channel.1.clone(),
+            self.route.clone(),
+            cmd_handler,
+        )?;
+        let mut r_cmd = self.in_cmd.clone();
+        let close_r = self.close.1.clone();
+        // dropped once the session ended on its own.
+        let (done_s, done_r) = channel::bounded::<()>(0);
+        let control = thread::spawn(move || loop {
+            let msg = select! {
+                recv(r_cmd) -> msg => match msg {
+                    Ok(msg) => msg,
+                    // only a handle can close the client now.
+                    Err(_) => {
+                        r_cmd = channel::never();
+                        continue;
                     }
-                }
-                let connected = lock.lock().unwrap();
-                if *connected == false {
-                    break;
-                }
+                },
+                recv(close_r) -> msg => msg.unwrap_or(ConnMsg::Close),
+                recv(done_r) -> _ => return,
+            };
+            match msg {
+                ConnMsg::Close => return closer.close(),
+                ConnMsg::Drain => return closer.drain(),
+                msg => debug!("connection msg received: {:?}", msg),
             }
         });
-        let (cmd_handler, cmd_readiness) = Registration::new2();
-        let r_cmd = self.in_cmd.clone();
-        let (s_close, r_close): (Sender<u32>, Receiver<u32>) = channel::unbounded();
-        let CONN2 = CONNECTED.clone();
-        thread::spawn(move || {
-            let lock = &*CONN2;
-            loop {
-                if let Ok(msg) = r_cmd.recv() {
-                    println!("connection msg received: {:?}", msg);
-                    let _ = s_close.send(1);
-                    cmd_readiness.set_readiness(Ready::readable());
+        let res = session.run();
+        drop(done_s);
+        let _ = control.join();
+        self.close_consumers();
+        res
+    }
+
+    // Session of a producer answering each publish received on pub_r.
+    pub(crate) fn publisher(
+        &mut self,
+        pub_r: Receiver<PubRequest>,
+    ) -> Result<(Session, SessionCloser, Route), ConnError> {
+        let cmd_handler = self
+            .cmd_handler
+            .take()
+            .ok_or_else(|| ConnError::Error("client is already running".to_owned()))?;
+        let (mut session, closer) = self.session(
+            self.addr.clone(),
+            self.cmd_channel.1.clone(),
+            self.route.clone(),
+            cmd_handler,
+        )?;
+        session.pub_r = Some(pub_r);
+        Ok((session, closer, self.route.clone()))
+    }
+
+    // Poll nsqlookupd and keep a session open for every nsqd producing the topic.
+    fn discover(&mut self) -> Result<(), ConnError> {
+        let interval = Duration::from_millis(self.config.lookupd_poll_interval);
+        let mut sessions: HashMap<String, SessionHandle> = HashMap::new();
+        let mut next_lookup = Instant::now();
+        loop {
+            if Instant::now() >= next_lookup {
+                next_lookup = Instant::now() + interval;
+                if let Err(e) = self.sync_sessions(&mut sessions) {
+                    // the sessions already started don't outlive the client.
+                    self.close_sessions(&mut sessions, Closing::Now);
+                    return Err(e);
                 }
-                let connected = lock.lock().unwrap();
-                if *connected == false {
-                    break;
+            }
+            self.balancer.redistribute();
+            let wait = RDY_REDISTRIBUTE_INTERVAL
+                .min(next_lookup.saturating_duration_since(Instant::now()));
+            let msg = select! {
+                recv(self.in_cmd) -> msg => match msg {
+                    Ok(msg) => Ok(msg),
+                    // only a handle can close the client now.
+                    Err(_) => self.close.1.recv_timeout(wait),
+                },
+                recv(self.close.1) -> msg => msg.map_err(|_| RecvTimeoutError::Disconnected),
+                default(wait) => Err(RecvTimeoutError::Timeout),
+            };
+            match msg {
+                Ok(ConnMsg::Close) => {
+                    self.close_sessions(&mut sessions, Closing::Now);
+                    return Ok(());
+                }
+                Ok(ConnMsg::Drain) => {
+                    self.close_sessions(&mut sessions, Closing::Drain);
+                    return Ok(());
                 }
+                Ok(msg) => debug!("connection msg received: {:?}", msg),
+                Err(_) => {}
             }
-        });
+        }
+    }
 
-        println!("Creating conn");
-        let mut conn = Conn::new(
-            self.config.clone(),
-            self.cmd_channel.1.clone(),
-            self.msg_channel.0.clone(),
-            self.out_info.clone(),
-            self.msg_timeout.clone(),
-        );
-        println!("Conn created");
-        let mut poll = Poll::new().unwrap();
-        let mut evts = Events::with_capacity(1024);
-        if let Err(e) = poll.register(&handler, CLIENT_TOKEN, Ready::writable(), PollOpt::edge()) {
-            error!("registering handler");
-            panic!("{}", e);
-        }
-        if let Err(e) = poll.register(&cmd_handler, CMD_TOKEN, Ready::all(), PollOpt::edge()) {
-            error!("registering handler");
-            panic!("{}", e);
-        }
-        conn.magic();
-        let mut nsqd_config: NsqdConfig = NsqdConfig::default();
-        let mut last_heartbeat = Instant::now();
-        let addr: String = self.addr.clone();
-        let mut socket = connect(addr, self.config.output_buffer_size);
-        poll.register(&socket, CONNECTION, Ready::writable(), PollOpt::edge());
-        let mut tls: u8 = 0;
-        loop {
-            if tls == 1 {
-                let connector = TlsConnector::new().unwrap();
-                let addr: String =
-                    self.addr.clone().split(':').collect::<Vec<&str>>()[0].to_owned();
-                let mut tls_stream = match connector.connect(addr.as_str(), socket) {
-                    Ok(s) => s,
-                    Err(e) => match e {
-                        HandshakeError::Failure(e) => {
-                            error!("error on tls handshake: {}", e);
-                            return Err(io::Error::new(io::ErrorKind::Other, e));
-                        }
-                        HandshakeError::WouldBlock(res) => {
-                            warn!("socket would block");
-                            thread::sleep(Duration::from_millis(1000));
-                            let mut res = res;
-                            loop {
-                                warn!("try handshake");
-                                match res.handshake() {
-                                    Ok(s) => break s,
-                                    Err(e) => match e {
-                                        HandshakeError::Failure(e) => {
-                                            error!("error on tls handshake: {}", e);
-                                            return Err(io::Error::new(io::ErrorKind::Other, e));
-                                        }
-                                        HandshakeError::WouldBlock(r) => {
-                                            warn!("socket would block");
-                                            thread::sleep(Duration::from_millis(1000));
-                                            res = r;
-                                            continue;
-                                        }
-                                    },
-                                }
-                            }
-                        }
-                    },
-                };
-                poll.reregister(
-                    tls_stream.get_ref(),
-                    CONNECTION,
-                    Ready::readable(),
-                    PollOpt::edge(),
-                );
-                loop {
-                    //if let Err(e) = poll.poll(&mut evts, Some(Duration::new(45, 0))) {
-                    if let Err(e) = poll.poll(&mut evts, None) {
-                        error!("polling tls events failed");
-                        panic!("{}", e);
-                    }
-                    for ev in &evts {
-                        debug!("event: {:?}", ev);
-                        if ev.token() == CMD_TOKEN {
-                            if let Ok(msg) = r_close.try_recv() {
-                                match msg {
-                                    1 => {
-                                        match tls_stream.shutdown() {
-                                            Ok(_) => debug!("TLS Connection Closed"),
-                                            Err(e) => error!("Error on TLS Closing: {:?}", e),
-                                        }
-                                        match self.msg_channel.0.send(BytesMsg(0, BytesMut::new()))
-                                        {
-                                            Ok(_) => debug!("Disconnet message sent to agent"),
-                                            Err(e) => {
-                                                error!("Error sending closing message: {:?}", e)
-                                            }
-                                        }
-                                        poll.reregister(
-                                            &cmd_handler,
-                                            CMD_TOKEN,
-                                            Ready::all(),
-                                            PollOpt::edge(),
-                                        );
-                                        return Ok(());
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            continue;
-                        }
-                        if ev.token() == CONNECTION {
-                            if ev.readiness().is_readable() {
-                                match conn.read(&mut tls_stream) {
-                                    Ok(0) => {
-                                        if conn.need_response {
-                                            poll.reregister(
-                                                tls_stream.get_ref(),
-                                                CONNECTION,
-                                                Ready::readable(),
-                                                PollOpt::edge(),
-                                            );
-                                        }
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        if e.kind() != std::io::ErrorKind::WouldBlock {
-                                            panic!("Error on reading socket: {:?}", e);
-                                        }
-                                        if let Err(e) =
-                                            self.out_info.send(ConnMsgInfo::IsConnected(ConnInfo {
-                                                connected: false,
-                                                last_time: 0,
-                                            }))
-                                        {
-                                            panic!("{}", e);
-                                        }
-                                        break;
-                                    }
-                                    _ => {}
-                                };
-                                if conn.state != State::Started {
-                                    match conn.state {
-                                        State::Tls => {
-                                            let resp = conn
-                                                .get_response(format!(
-                                                    "[{}] tls handshake failed",
-                                                    self.addr
-                                                ))
-                                                .unwrap();
-                                            info!("[{}] tls connection: {}", self.addr, resp);
-                                            if nsqd_config.auth_required {
-                                                if self.secret.is_none() {
-                                                    error!(
-                                                        "[{}] authentication required",
-                                                        self.addr
-                                                    );
-                                                    error!("secret token needed");
-                                                    process::exit(1)
-                                                }
-                                                conn.state = State::Auth;
-                                            } else {
-                                                conn.state = State::Subscribe;
-                                            }
-                                        }
-                                        State::Auth => {
-                                            let resp = conn
-                                                .get_response(format!(
-                                                    "[{}] authentication failed",
-                                                    self.addr
-                                                ))
-                                                .unwrap();
-                                            info!("[{}] authentication {}", self.addr, resp);
-                                            conn.state = State::Subscribe;
-                                        }
-                                        State::Subscribe => {
-                                            if self.topic.len() == 0 && self.channel.len() == 0 {
-                                                conn.state = State::Started;
-                                            } else {
-                                                let resp = conn
-                                                    .get_response(format!(
-                                                        "[{}] authentication failed",
-                                                        self.addr
-                                                    ))
-                                                    .unwrap();
-                                                info!(
-                                                    "[{}] subscribe channel: {} topic: {} {}",
-                                                    self.addr, self.channel, self.topic, resp
-                                                );
-                                                conn.state = State::Rdy;
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                    conn.need_response = false;
-                                }
-                                poll.reregister(
-                                    tls_stream.get_ref(),
-                                    CONNECTION,
-                                    Ready::writable(),
-                                    PollOpt::edge(),
-                                );
-                            } else if conn.state != State::Started {
-                                match conn.state {
-                                    State::Auth => match &self.secret {
-                                        Some(s) => {
-                                            let secret = s.clone();
-                                            conn.auth(secret.into());
-                                        }
-                                        None => {}
-                                    },
-                                    State::Subscribe => {
-                                        if self.topic.len() == 0 && self.channel.len() == 0 {
-                                            conn.state = State::Started;
-                                        } else {
-                                            conn.subscribe(
-                                                self.topic.clone(),
-                                                self.channel.clone(),
-                                            );
-                                        }
-                                    }
-                                    State::Rdy => {
-                                        conn.rdy(self.rdy);
-                                    }
-                                    _ => {}
-                                }
-                                if let Err(e) = conn.write(&mut tls_stream) {
-                                    error!("writing on socket: {:?}", e);
-                                };
-                                if conn.need_response {
-                                    poll.reregister(
-                                        tls_stream.get_ref(),
-                                        CONNECTION,
-                                        Ready::readable(),
-                                        PollOpt::edge(),
-                                    );
-                                } else {
-                                    poll.reregister(
-                                        tls_stream.get_ref(),
-                                        CONNECTION,
-                                        Ready::writable(),
-                                        PollOpt::edge(),
-                                    );
-                                };
-                            } else {
-                                if conn.heartbeat {
-                                    last_heartbeat = Instant::now();
-                                    println!("heartbeat received");
-                                    conn.write_cmd(Nop);
-                                    if let Err(e) = conn.write(&mut tls_stream) {
-                                        error!("writing on socket: {:?}", e);
-                                    }
-                                    println!("NOP sent");
-                                    conn.heartbeat_done();
-                                }
-                                conn.write_messages(&mut tls_stream);
-                                poll.reregister(
-                                    tls_stream.get_ref(),
-                                    CONNECTION,
-                                    Ready::readable(),
-                                    PollOpt::edge(),
-                                );
-                            }
-                        } else {
-                            conn.write_messages(&mut tls_stream);
-                        }
-                    }
+    // Close every session, then the consumers once the sessions returned.
+    fn close_sessions(&self, sessions: &mut HashMap<String, SessionHandle>, closing: Closing) {
+        for (closer, _) in sessions.values() {
+            closer.send(closing);
+        }
+        for (_, (_, handle)) in sessions.drain() {
+            let _ = handle.join();
+        }
+        self.close_consumers();
+    }
+
+    // Open sessions to new nsqd nodes and close the ones which disappeared from nsqlookupd.
+    // The sessions started before an error are kept in sessions for the caller to close.
+    fn sync_sessions(
+        &self,
+        sessions: &mut HashMap<String, SessionHandle>,
+    ) -> Result<(), ConnError> {
+        // sessions which gave up reconnecting are started again if nsqd is still advertised.
+        let finished: Vec<String> = sessions
+            .iter()
+            .filter(|(_, (_, handle))| handle.is_finished())
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in finished {
+            if let Some((_, handle)) = sessions.remove(&addr) {
+                if let Ok(Err(e)) = handle.join() {
+                    error!("[{}] connection closed: {}", addr, e);
                 }
             }
-            if let Err(e) = poll.poll(&mut evts, Some(Duration::new(45, 0))) {
-                error!("polling events failed");
-                panic!("{}", e);
+        }
+        let nodes = match self.lookup_nodes() {
+            Some(nodes) => nodes,
+            None => {
+                warn!("no nsqlookupd reachable, keeping current connections");
+                return Ok(());
             }
-            // if last_heartbeat is not seen shutdown occurred.
-            if last_heartbeat.elapsed() > Duration::new(45, 0) {
-                // send fake message as closed connection event.
-                let _ = self.msg_channel.0.send(BytesMsg(0, BytesMut::new()));
+        };
+        let mut res = Ok(());
+        for addr in nodes.iter() {
+            if sessions.contains_key(addr) {
+                continue;
             }
-            for ev in &evts {
-                debug!("event: {:?}", ev);
-                if ev.token() == CMD_TOKEN {
-                    match r_close.try_recv() {
-                        Ok(msg) => match msg {
-                            1 => {
-                                let _ = socket.shutdown(Shutdown::Both);
-                                let _ = self.msg_channel.0.send(BytesMsg(0, BytesMut::new()));
-                                poll.reregister(
-                                    &cmd_handler,
-                                    CMD_TOKEN,
-                                    Ready::all(),
-                                    PollOpt::edge(),
-                                );
-                                return Ok(());
-                            }
-                            _ => {}
-                        },
-                        Err(e) => error!("error on Disconnect: {:?}", e),
-                    }
-                    continue;
+            info!("[{}] new nsqd found for topic {}", addr, self.topic);
+            let cmd_channel = CmdChannel::new();
+            let (cmd_handler, waker) = Registration::new2();
+            let route = Route::new(cmd_channel.0, waker)
+                .high_water_mark(self.config.write_high_water_mark as usize);
+            match self.session(addr.clone(), cmd_channel.1, route, cmd_handler) {
+                Ok((mut session, closer)) => {
+                    let handle = thread::spawn(move || session.run());
+                    sessions.insert(addr.clone(), (closer, handle));
                 }
-                if ev.token() == CONNECTION {
-                    if ev.readiness().is_readable() {
-                        match conn.read(&mut socket) {
-                            Ok(0) => {
-                                if conn.need_response {
-                                    poll.reregister(
-                                        &socket,
-                                        CONNECTION,
-                                        Ready::readable(),
-                                        PollOpt::edge(),
-                                    );
-                                }
-                                break;
-                            }
-                            Err(e) => {
-                                if e.kind() != std::io::ErrorKind::WouldBlock {
-                                    panic!("Error on reading socket: {:?}", e);
-                                }
-                                if let Err(e) =
-                                    self.out_info.send(ConnMsgInfo::IsConnected(ConnInfo {
-                                        connected: false,
-                                        last_time: 0,
-                                    }))
-                                {
-                                    panic!("{}", e);
-                                }
-                                break;
-                            }
-                            _ => {}
-                        };
-                        if conn.state != State::Started {
-                            match conn.state {
-                                State::Identify => {
-                                    let resp = conn
-                                        .get_response(format!(
-                                            "[{}] failed to indentify",
-                                            self.addr
-                                        ))
-                                        .unwrap();
-                                    nsqd_config = serde_json::from_str(&resp)
-                                        .expect("failed to decode identify response");
-                                    info!("[{}] configuration: {:#?}", self.addr, nsqd_config);
-                                    conn.msg_timeout = nsqd_config.msg_timeout;
-                                    if nsqd_config.tls_v1 {
-                                        conn.tls_enabled(&mut tls);
-                                        //#[cfg(target_os = "windows")]
-                                        //poll.deregister(&socket);
-                                        break;
-                                    };
-                                    if nsqd_config.auth_required {
-                                        if self.secret.is_none() {
-                                            error!("[{}] authentication required", self.addr);
-                                            error!("secret token needed");
-                                            process::exit(1)
-                                        }
-                                        conn.state = State::Auth;
-                                    } else {
-                                        conn.state = State::Subscribe;
-                                    }
-                                }
-                                State::Tls => {
-                                    let resp = conn
-                                        .get_response(format!(
-                                            "[{}] tls handshake failed",
-                                            self.addr
-                                        ))
-                                        .unwrap();
-                                    info!("[{}] tls connection: {}", self.addr, resp);
-                                    if nsqd_config.auth_required {
-                                        if self.secret.is_none() {
-                                            error!("[{}] authentication required", self.addr);
-                                            error!("secret token needed");
-                                            process::exit(1)
-                                        }
-                                        conn.state = State::Auth;
-                                    } else {
-                                        conn.state = State::Subscribe;
-                                    }
-                                }
-                                State::Auth => {
-                                    let resp = conn
-                                        .get_response(format!(
-                                            "[{}] authentication failed",
-                                            self.addr
-                                        ))
-                                        .unwrap();
-                                    info!("[{}] authentication {}", self.addr, resp);
-                                    conn.state = State::Subscribe;
-                                }
-                                State::Subscribe => {
-                                    let resp = conn
-                                        .get_response(format!(
-                                            "[{}] authentication failed",
-                                            self.addr
-                                        ))
-                                        .unwrap();
-                                    info!(
-                                        "[{}] subscribe channel: {} topic: {} {}",
-                                        self.addr, self.channel, self.topic, resp
-                                    );
-                                    conn.state = State::Rdy;
-                                }
-                                _ => {}
-                            }
-                            conn.need_response = false;
-                        }
-                        poll.reregister(&socket, CONNECTION, Ready::writable(), PollOpt::edge());
-                    } else if conn.state != State::Started {
-                        match conn.state {
-                            State::Identify => {
-                                conn.identify();
-                            }
-                            State::Auth => match &self.secret {
-                                Some(s) => {
-                                    let secret = s.clone();
-                                    conn.auth(secret.into());
-                                }
-                                None => {}
-                            },
-                            State::Subscribe => {
-                                conn.subscribe(self.topic.clone(), self.channel.clone());
-                            }
-                            State::Rdy => {
-                                conn.rdy(self.rdy);
-                            }
-                            _ => {}
-                        }
-                        if let Err(e) = conn.write(&mut socket) {
-                            error!("writing on socket: {:?}", e);
-                        };
-                        if conn.need_response {
-                            poll.reregister(
-                                &socket,
-                                CONNECTION,
-                                Ready::readable(),
-                                PollOpt::edge(),
-                            );
-                        } else {
-                            poll.reregister(
-                                &socket,
-                                CONNECTION,
-                                Ready::writable(),
-                                PollOpt::edge(),
-                            );
-                        };
-                    } else {
-                        if conn.heartbeat {
-                            last_heartbeat = Instant::now();
-                            conn.write_cmd(Nop);
-                            if let Err(e) = conn.write(&mut socket) {
-                                error!("writing on socket: {:?}", e);
-                            }
-                            conn.heartbeat_done();
-                        }
-                        conn.write_messages(&mut socket);
-                        poll.reregister(&socket, CONNECTION, Ready::readable(), PollOpt::edge());
-                    }
-                } else {
-                    conn.write_messages(&mut socket);
+                Err(e) => {
+                    error!("[{}] cannot start session: {}", addr, e);
+                    res = res.and(Err(e));
                 }
             }
         }
+        let gone: Vec<String> = sessions
+            .keys()
+            .filter(|addr| !nodes.contains(*addr))
+            .cloned()
+            .collect();
+        for addr in gone {
+            info!("[{}] nsqd no longer produces topic {}", addr, self.topic);
+            if let Some((closer, _)) = sessions.remove(&addr) {
+                closer.close();
+            }
+        }
+        res
+    }
+
+    // Addresses of the nsqd producing the topic, None if no nsqlookupd answered.
+    fn lookup_nodes(&self) -> Option<HashSet<String>> {
+        let mut nodes = HashSet::new();
+        let mut answered = false;
+        for lookupd in self.lookupd.iter() {
+            match lookup(lookupd, &self.topic, LOOKUP_TIMEOUT) {
+                Ok(resp) => {
+                    answered = true;
+                    nodes.extend(resp.producers.iter().map(|p| p.addr()));
+                }
+                Err(e) => error!("[{}] lookup failed: {}", lookupd, e),
+            }
+        }
+        if answered {
+            Some(nodes)
+        } else {
+            None
+        }
+    }
+
+    fn session(
+        &self,
+        addr: String,
+        cmd_r: Receiver<Cmd>,
+        route: Route,
+        cmd_handler: Registration,
+    ) -> Result<(Session, SessionCloser), ConnError> {
+        let poll = Poll::new()?;
+        poll.register(
+            &cmd_handler,
+            CLIENT_TOKEN,
+            Ready::writable(),
+            PollOpt::edge(),
+        )?;
+        let (close_handler, close_waker) = Registration::new2();
+        poll.register(&close_handler, CMD_TOKEN, Ready::all(), PollOpt::edge())?;
+        let (close_s, close_r) = channel::unbounded();
+        let session = Session {
+            addr,
+            topic: self.topic.clone(),
+            channel: self.channel.clone(),
+            secret: self.secret.clone().map(Into::into),
+            balancer: self.balancer.clone(),
+            config: self.config.clone(),
+            msg_s: self.msg_channel.0.clone(),
+            cmd_r,
+            route,
+            out_info: self.out_info.clone(),
+            close_r,
+            poll,
+            _cmd_handler: cmd_handler,
+            close_handler,
+            msg_timeout: self.msg_timeout,
+            max_rdy_count: u32::MAX,
+            last_msg: Instant::now(),
+            reconnect_attempts: 0,
+            pub_r: None,
+            pending: VecDeque::new(),
+            state: self.state.clone(),
+            started: false,
+            draining: None,
+            close_wait: false,
+        };
+        let closer = SessionCloser {
+            s: close_s,
+            waker: close_waker,
+        };
+        Ok((session, closer))
+    }
+
+    // send fake message as closed connection event.
+    fn close_consumers(&self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        match self
+            .msg_channel
+            .0
+            .send(BytesMsg(0, BytesMut::new(), self.route.clone()))
+        {
+            Ok(_) => debug!("Disconnet message sent to agent"),
+            Err(e) => error!("Error sending closing message: {:?}", e),
+        }
     }
 
-    #[cfg(not(feature = "async"))]
     pub fn spawn<H: Consumer>(&mut self, n_threads: usize, reader: H) {
         for _i in 0..n_threads {
             let mut boxed = Box::new(reader.clone());
-            let cmd = self.cmd_channel.0.clone();
+            let route = self.route.clone();
             let msg_ch = self.msg_channel.1.clone();
-            let sentinel = self.sentinel.0.clone();
-            //let max_attemps = self.max_attemps;
-            //let conn_s = self.connected_r.clone();
-            let connected_var = CONNECTED.clone();
+            let config = self.config.clone();
+            let max_attemps = reader.max_attemps().unwrap_or(self.max_attemps);
+            let balancer = self.balancer.clone();
+            let msg_s = self.msg_channel.0.clone();
             thread::spawn(move || {
-                let mut ctx = Context::new(cmd, sentinel);
-                let lock = &*connected_var;
+                let mut ctx = Context::new(route, &config).with_balancer(balancer);
                 info!("Handler spawned");
                 loop {
-                    let mut connected = lock.lock().unwrap();
-                    if !*connected {
-                        debug!("closing thread");
-                        break;
-                    }
                     if let Ok(ref mut msg) = msg_ch.recv() {
-                        if msg.1.len() == 0 {
+                        // commands are sent back to the connection which delivered the message.
+                        ctx.route = msg.2.clone();
+                        if msg.1.is_empty() {
                             debug!("closing thread");
-                            *connected = false;
+                            // passed on to the other handlers of the client.
+                            let _ = msg_s.send(BytesMsg(0, BytesMut::new(), msg.2.clone()));
                             boxed.on_close(&mut ctx);
                             break;
                         };
                         debug!("I'm on loop");
                         let timeout = msg.0;
                         let msg = decode_msg(&mut msg.1);
-                        boxed.on_msg(
-                            Msg {
-                                timeout,
-                                timestamp: msg.0,
-                                attemps: msg.1,
-                                id: msg.2,
-                                body: msg.3,
-                            },
-                            &mut ctx,
-                        );
+                        let msg = Msg {
+                            timeout,
+                            timestamp: msg.0,
+                            attemps: msg.1,
+                            id: msg.2,
+                            body: msg.3,
+                        };
+                        deliver(&mut *boxed, msg, max_attemps, &mut ctx);
                     }
                 }
             });
         }
     }
 
-    #[cfg(not(feature = "async"))]
+    /// Start n_threads handlers answering nsqd from the outcome of
+    /// [Handler::handle](trait.Handler.html#tymethod.handle).
+    pub fn spawn_handler<H: Handler>(&mut self, n_threads: usize, handler: H) {
+        let mut disposer = Disposer::new(handler);
+        if let Some(dead_letter) = self.dead_letter.clone() {
+            let max_attemps = disposer.max_attemps().unwrap_or(self.max_attemps);
+            disposer = disposer.dead_letter(dead_letter, max_attemps);
+        }
+        self.spawn(n_threads, disposer);
+    }
+
     pub fn spawn_producer<P: Producer>(&mut self, n_threads: usize, prod: P) {
         for _i in 0..n_threads {
             let boxed = Box::new(prod);
-            let cmd = self.cmd_channel.0.clone();
+            let route = self.route.clone();
             //let msg_ch = self.msg_channel.1.clone();
-            let sentinel = self.sentinel.0.clone();
+            let config = self.config.clone();
             //let max_attemps = self.max_attemps;
-            //let conn_s = self.connected_r.clone();
-            let connected_var = CONNECTED.clone();
+            let state = self.state.clone();
             thread::spawn(move || {
-                let mut ctx = Context::new(cmd, sentinel);
-                let lock = &*connected_var;
+                let mut ctx = Context::new(route, &config);
                 info!("Handler spawned");
                 loop {
-                    let connected = lock.lock().unwrap();
-                    if !*connected {
+                    if state.closed.load(Ordering::SeqCst) {
                         debug!("closing thread");
                         break;
                     }
@@ -685,23 +634,1212 @@ where
     }
 }
 
+// A single nsqd connection, reconnected until it is closed or the retry policy gives up.
+pub(crate) struct Session {
+    addr: String,
+    topic: String,
+    channel: String,
+    secret: Option<String>,
+    balancer: Arc<RdyBalancer>,
+    config: Config,
+    msg_s: Sender<BytesMsg>,
+    cmd_r: Receiver<Cmd>,
+    route: Route,
+    out_info: Sender<ConnMsgInfo>,
+    close_r: Receiver<Closing>,
+    poll: Poll,
+    // registrations must live as long as the poll they wake up.
+    _cmd_handler: Registration,
+    close_handler: Registration,
+    msg_timeout: u64,
+    max_rdy_count: u32,
+    last_msg: Instant,
+    reconnect_attempts: u32,
+    // publishes of a Publisher, answered in the order they are written.
+    pub_r: Option<Receiver<PubRequest>>,
+    pending: VecDeque<Sender<Result<(), PubError>>>,
+    state: Arc<ClientState>,
+    // the connection is counted in the client state.
+    started: bool,
+    // deadline of a drain asked, the connection closes once CLOSE_WAIT is received
+    // and nothing is in flight.
+    draining: Option<Instant>,
+    close_wait: bool,
+}
+
+impl Session {
+    pub(crate) fn run(&mut self) -> Result<(), ConnError> {
+        let mut backoff = ExponentialBackoff {
+            max_elapsed_time: None,
+            ..Default::default()
+        };
+        loop {
+            let res = connect(self.addr.clone(), self.config.output_buffer_size)
+                .and_then(|socket| self.connection(socket));
+            self.balancer.remove(&self.addr);
+            if self.started {
+                self.started = false;
+                self.state.connections.fetch_sub(1, Ordering::SeqCst);
+            }
+            // the bytes left behind are lost with the connection, don't keep the handlers waiting.
+            self.route.set_backlog(0);
+            // nsqd won't answer the publishes written on the old connection,
+            // the ones queued meanwhile don't wait for the next one.
+            for reply in self.pending.drain(..) {
+                let _ = reply.send(Err(PubError::ConnectionLost));
+            }
+            if let Some(pub_r) = self.pub_r.as_ref() {
+                for req in pub_r.try_iter() {
+                    let _ = req.reply.send(Err(PubError::ConnectionLost));
+                }
+            }
+            if self.draining.take().is_some() {
+                // nsqd requeues the messages left in flight.
+                if let Err(e) = res {
+                    error!("[{}] connection lost while draining: {}", self.addr, e);
+                }
+                return Ok(());
+            }
+            let err = match res {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if !err.is_transient() {
+                // protocol, auth or configuration errors: connecting again would fail the same way.
+                error!("[{}] connection failed: {}", self.addr, err);
+                return Err(err);
+            }
+            error!("[{}] connection lost: {}", self.addr, err);
+            let _ = self.out_info.send(ConnMsgInfo::IsConnected(ConnInfo {
+                connected: false,
+                last_time: Utc::now().timestamp(),
+            }));
+            if self.reconnect_attempts == 0 {
+                backoff.reset();
+            }
+            self.reconnect_attempts += 1;
+            if let Some(max) = self.config.max_reconnect_attempts {
+                if self.reconnect_attempts > max {
+                    error!(
+                        "[{}] giving up after {} reconnection attempts",
+                        self.addr, max
+                    );
+                    return Err(err);
+                }
+            }
+            // a close asked meanwhile doesn't wait for the backoff to be over.
+            let closed = match backoff.next_backoff() {
+                Some(delay) => self.close_r.recv_timeout(delay).is_ok(),
+                None => self.close_r.try_recv().is_ok(),
+            };
+            if closed {
+                return Ok(());
+            }
+            info!(
+                "[{}] reconnecting (attempt {})",
+                self.addr, self.reconnect_attempts
+            );
+            let _ = self.out_info.send(ConnMsgInfo::Reconnect(ReconnectInfo {
+                addr: self.addr.clone(),
+                attempt: self.reconnect_attempts,
+            }));
+        }
+    }
+
+    // maximum time without reading anything from nsqd before the connection is considered lost.
+    fn read_timeout(&self) -> Option<Duration> {
+        if self.config.heartbeat_interval <= 0 {
+            return None;
+        }
+        Some(Duration::from_millis(
+            self.config.heartbeat_interval as u64 * 2,
+        ))
+    }
+
+    // send the RDY count decided by the balancer once the connection is started.
+    fn update_rdy<STREAM: Read + Write>(&mut self, conn: &mut Conn, stream: &mut STREAM) {
+        if self.draining.is_some() {
+            return;
+        }
+        let rdy = match self.balancer.take_pending(&self.addr) {
+            Some(rdy) => Some(rdy),
+            None if conn.last_msg() > self.last_msg => {
+                self.last_msg = conn.last_msg();
+                self.balancer
+                    .on_msg(&self.addr, conn.last_msg(), conn.in_flight())
+            }
+            None => None,
+        };
+        if let Some(rdy) = rdy {
+            conn.rdy(rdy.min(self.max_rdy_count));
+            if let Err(e) = conn.write(stream) {
+                error!("writing on socket: {:?}", e);
+            }
+        }
+    }
+
+    // write the publishes queued, their responses come back in the same order.
+    fn write_publishes<STREAM: Read + Write>(&mut self, conn: &mut Conn, stream: &mut STREAM) {
+        let pub_r = match self.pub_r.as_ref() {
+            Some(pub_r) => pub_r,
+            None => return,
+        };
+        let reqs: Vec<PubRequest> = pub_r.try_iter().collect();
+        if reqs.is_empty() {
+            return;
+        }
+        for req in reqs {
+            conn.write_cmd(req.cmd);
+            self.pending.push_back(req.reply);
+        }
+        if let Err(e) = conn.write(stream) {
+            error!("writing on socket: {:?}", e);
+        }
+    }
+
+    // responses received once started go to the pending publishes.
+    // FIN, REQ and TOUCH failures are only logged, nsqd closes the connection after the others.
+    fn check_responses(&mut self, conn: &mut Conn) -> Result<(), ConnError> {
+        for resp in conn.responses.drain(..) {
+            let e = match resp {
+                Response::Response(ref resp) if resp == CLOSE_WAIT => {
+                    self.close_wait = true;
+                    continue;
+                }
+                Response::Response(_) => {
+                    if let Some(reply) = self.pending.pop_front() {
+                        let _ = reply.send(Ok(()));
+                    }
+                    continue;
+                }
+                Response::Error(e) => e,
+            };
+            let err = ConnError::from_frame(&e);
+            if !err.is_fatal() {
+                error!("[{}] {}", self.addr, err);
+                continue;
+            }
+            if let Some(reply) = self.pending.pop_front() {
+                let _ = reply.send(Err(PubError::Nsqd(err)));
+                // the publish is refused, not the connection: connect again for the next ones.
+                return Err(ConnError::IoError(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("connection closed by nsqd after {}", e),
+                )));
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Drive the TLS handshake from the socket readiness events.
+    // Returns None when the session was asked to close meanwhile.
+    fn tls_handshake(
+        &mut self,
+        socket: TcpStream,
+        server_name: &str,
+        evts: &mut Events,
+    ) -> Result<Option<TlsStream>, ConnError> {
+        // no reregistration until the end: edge events only come when the socket changes.
+        self.poll.reregister(
+            &socket,
+            CONNECTION,
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        )?;
+        let deadline = Instant::now() + Duration::from_millis(self.config.tls_handshake_timeout);
+        let mut handshake = TlsStream::connect(&self.config, server_name, socket)?;
+        loop {
+            let mid = match handshake {
+                tls::Handshake::Done(stream) => {
+                    debug!("[{}] tls handshake done", self.addr);
+                    return Ok(Some(stream));
+                }
+                tls::Handshake::InProgress(mid) => mid,
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ConnError::IoError(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "tls handshake timed out",
+                )));
+            }
+            self.poll.poll(evts, Some(deadline - now))?;
+            let mut ready = false;
+            for ev in evts.iter() {
+                if ev.token() == CMD_TOKEN {
+                    if self.close_r.try_recv().is_ok() {
+                        let _ = mid.get_ref().shutdown(Shutdown::Both);
+                        self.poll.reregister(
+                            &self.close_handler,
+                            CMD_TOKEN,
+                            Ready::all(),
+                            PollOpt::edge(),
+                        )?;
+                        return Ok(None);
+                    }
+                } else if ev.token() == CONNECTION {
+                    ready = true;
+                }
+            }
+            handshake = if ready {
+                mid.handshake()?
+            } else {
+                tls::Handshake::InProgress(mid)
+            };
+        }
+    }
+
+    fn started(&mut self, done: Done, conn: &mut Conn) {
+        self.reconnect_attempts = 0;
+        self.started = true;
+        self.state.connections.fetch_add(1, Ordering::SeqCst);
+        let nsqd_config = done.nsqd_config();
+        conn.msg_timeout = nsqd_config.msg_timeout;
+        conn.started();
+        self.max_rdy_count = nsqd_config.max_rdy_count;
+        if let Some(auth) = done.auth() {
+            let _ = self.out_info.send(ConnMsgInfo::Auth(AuthInfo {
+                addr: self.addr.clone(),
+                resp: auth.clone(),
+            }));
+        }
+        let _ = self.out_info.send(ConnMsgInfo::IsConnected(ConnInfo {
+            connected: true,
+            last_time: Utc::now().timestamp(),
+        }));
+    }
+
+    fn params(&self) -> Params {
+        Params {
+            addr: self.addr.clone(),
+            config: self.config.clone(),
+            topic: self.topic.clone(),
+            channel: self.channel.clone(),
+            secret: self.secret.clone(),
+        }
+    }
+
+    // Go on with the handshake as far as the responses read allow.
+    // Returns None once it's done, the state waiting for the stream otherwise.
+    fn handshake(
+        &mut self,
+        mut handshake: Handshake,
+        conn: &mut Conn,
+    ) -> Result<Option<Handshake>, ConnError> {
+        loop {
+            handshake = match handshake {
+                Handshake::Waiting(state) => {
+                    if conn.responses.is_empty() {
+                        return Ok(Some(Handshake::Waiting(state)));
+                    }
+                    let resp = conn.get_response(format!("[{}] handshake failed", self.addr))?;
+                    state.on_response(&resp, conn.w_buf())?
+                }
+                Handshake::Tls(tls) => return Ok(Some(Handshake::Tls(tls))),
+                Handshake::Compress(compress) => {
+                    let (compression, state) = compress.enable();
+                    conn.compress(compression)?;
+                    Handshake::Waiting(state)
+                }
+                Handshake::Rdy(rdy) => {
+                    let count = self.balancer.add(&self.addr, self.route.waker.clone());
+                    Handshake::Done(rdy.rdy(count, conn.w_buf()))
+                }
+                Handshake::Done(done) => {
+                    self.started(done, conn);
+                    return Ok(None);
+                }
+            };
+        }
+    }
+
+    // Drive a single connection from MAGIC to message delivery.
+    // Returns Ok when the session was asked to close, Err when the connection was lost.
+    fn connection(&mut self, socket: TcpStream) -> Result<(), ConnError> {
+        let mut conn = Conn::new(
+            self.config.clone(),
+            self.cmd_r.clone(),
+            self.msg_s.clone(),
+            self.out_info.clone(),
+            self.route.clone(),
+            self.msg_timeout,
+        );
+        let mut evts = Events::with_capacity(1024);
+        self.last_msg = conn.last_msg();
+        let read_timeout = self.read_timeout();
+        let mut last_read = Instant::now();
+        self.close_wait = false;
+        let identify = Magic::new(self.params()).identify(conn.w_buf())?;
+        let mut handshake = Some(Handshake::Waiting(Waiting::Identify(identify)));
+        self.poll
+            .register(&socket, CONNECTION, Ready::writable(), PollOpt::edge())?;
+        let mut stream = Stream::Plain(socket);
+        loop {
+            if let Some(Handshake::Tls(_)) = handshake {
+                let tls = match handshake.take() {
+                    Some(Handshake::Tls(tls)) => tls,
+                    _ => unreachable!(),
+                };
+                let socket = match stream {
+                    Stream::Plain(socket) => socket,
+                    Stream::Tls(_) => {
+                        return Err(ConnError::Error("tls already enabled".to_owned()))
+                    }
+                };
+                stream = match self.tls_handshake(socket, &tls.server_name(), &mut evts)? {
+                    Some(tls_stream) => Stream::Tls(tls_stream),
+                    None => return Ok(()),
+                };
+                handshake = Some(Handshake::Waiting(tls.upgraded()));
+                last_read = Instant::now();
+                self.poll.reregister(
+                    stream.get_ref(),
+                    CONNECTION,
+                    Ready::readable(),
+                    PollOpt::edge(),
+                )?;
+            }
+            // wake up to probe once the backoff is over.
+            let mut timeout = match (read_timeout, self.balancer.backoff_tick()) {
+                (Some(read), Some(backoff)) => Some(read.min(backoff)),
+                (read, backoff) => read.or(backoff),
+            };
+            if let Some(deadline) = self.draining {
+                let left = deadline.saturating_duration_since(Instant::now());
+                timeout = Some(timeout.map_or(left, |timeout| timeout.min(left)));
+            }
+            self.poll.poll(&mut evts, timeout)?;
+            check_read_timeout(last_read, read_timeout)?;
+            for ev in evts.iter() {
+                debug!("event: {:?}", ev);
+                if ev.token() == CMD_TOKEN {
+                    let closing = match self.close_r.try_recv() {
+                        Ok(closing) => closing,
+                        Err(_) => continue,
+                    };
+                    self.poll.reregister(
+                        &self.close_handler,
+                        CMD_TOKEN,
+                        Ready::all(),
+                        PollOpt::edge(),
+                    )?;
+                    // producers and connections not started yet have nothing to drain.
+                    let drain = closing == Closing::Drain
+                        && handshake.is_none()
+                        && !self.topic.is_empty()
+                        && self.draining.is_none();
+                    if !drain {
+                        stream.shutdown();
+                        return Ok(());
+                    }
+                    info!(
+                        "[{}] draining, {} messages in flight",
+                        self.addr,
+                        conn.in_flight()
+                    );
+                    let timeout = Duration::from_millis(self.config.drain_timeout);
+                    self.draining = Some(Instant::now() + timeout);
+                    conn.rdy(0);
+                    conn.write_cmd(Cls);
+                    if let Err(e) = conn.write(&mut stream) {
+                        error!("writing on socket: {:?}", e);
+                    }
+                    continue;
+                }
+                if ev.token() != CONNECTION {
+                    // commands sent by the handlers.
+                    if handshake.is_none() {
+                        self.update_rdy(&mut conn, &mut stream);
+                        conn.write_messages(&mut stream);
+                        self.write_publishes(&mut conn, &mut stream);
+                    }
+                    continue;
+                }
+                if ev.readiness().is_readable() {
+                    match conn.read(&mut stream) {
+                        Ok(0) => {
+                            return Err(ConnError::IoError(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "connection closed by nsqd",
+                            )));
+                        }
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::WouldBlock {
+                                return Err(e.into());
+                            }
+                            self.poll.reregister(
+                                stream.get_ref(),
+                                CONNECTION,
+                                Ready::readable(),
+                                PollOpt::edge(),
+                            )?;
+                            continue;
+                        }
+                        _ => last_read = Instant::now(),
+                    };
+                    match handshake.take() {
+                        Some(state) => {
+                            handshake = self.handshake(state, &mut conn)?;
+                            if let Some(Handshake::Tls(_)) = handshake {
+                                // the socket is handed to the tls handshake.
+                                break;
+                            }
+                            if let Err(e) = conn.write(&mut stream) {
+                                error!("writing on socket: {:?}", e);
+                            }
+                        }
+                        None => self.check_responses(&mut conn)?,
+                    }
+                    self.poll.reregister(
+                        stream.get_ref(),
+                        CONNECTION,
+                        Ready::writable(),
+                        PollOpt::edge(),
+                    )?;
+                } else if handshake.is_some() {
+                    // connected: MAGIC and IDENTIFY can go.
+                    if let Err(e) = conn.write(&mut stream) {
+                        error!("writing on socket: {:?}", e);
+                    }
+                    self.poll.reregister(
+                        stream.get_ref(),
+                        CONNECTION,
+                        interest(&conn),
+                        PollOpt::edge(),
+                    )?;
+                } else {
+                    if conn.wants_write() {
+                        // what the socket didn't take last time goes first.
+                        conn.write(&mut stream)?;
+                    }
+                    if conn.heartbeat {
+                        conn.write_cmd(Nop);
+                        if let Err(e) = conn.write(&mut stream) {
+                            error!("writing on socket: {:?}", e);
+                        }
+                        conn.heartbeat_done();
+                    }
+                    self.update_rdy(&mut conn, &mut stream);
+                    conn.write_messages(&mut stream);
+                    self.write_publishes(&mut conn, &mut stream);
+                    self.poll.reregister(
+                        stream.get_ref(),
+                        CONNECTION,
+                        interest(&conn),
+                        PollOpt::edge(),
+                    )?;
+                }
+            }
+            if conn.wants_write() {
+                // a write of the handlers left bytes behind, wait for the socket to take them.
+                self.poll.reregister(
+                    stream.get_ref(),
+                    CONNECTION,
+                    Ready::readable() | Ready::writable(),
+                    PollOpt::edge(),
+                )?;
+            }
+            if let Some(deadline) = self.draining {
+                // the last FIN and REQ of the handlers go before closing.
+                conn.write_messages(&mut stream);
+                let done = self.close_wait && conn.in_flight() == 0 && !conn.wants_write();
+                if done || Instant::now() >= deadline {
+                    if !done {
+                        warn!(
+                            "[{}] drain timed out, {} messages in flight",
+                            self.addr,
+                            conn.in_flight()
+                        );
+                    }
+                    stream.shutdown();
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// readiness to wait for on the nsqd socket.
+fn interest(conn: &Conn) -> Ready {
+    if conn.wants_write() {
+        Ready::readable() | Ready::writable()
+    } else {
+        Ready::readable()
+    }
+}
+
+// nsqd socket, upgraded to TLS when nsqd asks for it.
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    fn get_ref(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(tls) => tls.get_ref(),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        match self {
+            Stream::Plain(socket) => {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            Stream::Tls(tls) => match tls.shutdown() {
+                Ok(_) => debug!("TLS Connection Closed"),
+                Err(e) => error!("Error on TLS Closing: {:?}", e),
+            },
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Context {
-    cmd_s: Sender<Cmd>,
-    sentinel: Sender<()>,
+    route: Route,
+    max_req_timeout: Duration,
+    requeue_delay: Duration,
+    max_requeue_delay: Duration,
+    // backoff driven by the outcome of handlers.
+    balancer: Option<Arc<RdyBalancer>>,
 }
 
 impl Context {
-    fn new(cmd_s: Sender<Cmd>, sentinel: Sender<()>) -> Context {
+    pub(crate) fn new(route: Route, config: &Config) -> Context {
+        let max_req_timeout = Duration::from_millis(config.max_req_timeout);
         Context {
-            cmd_s,
-            sentinel: sentinel,
+            route,
+            max_req_timeout,
+            requeue_delay: Duration::from_millis(config.requeue_delay),
+            max_requeue_delay: Duration::from_millis(config.max_requeue_delay).min(max_req_timeout),
+            balancer: None,
         }
     }
 
+    pub(crate) fn with_balancer(mut self, balancer: Arc<RdyBalancer>) -> Context {
+        self.balancer = Some(balancer);
+        self
+    }
+
+    /// Send a command to nsqd.
+    ///
+    /// Commands are written on the connection which delivered the last message
+    /// received by the handler. Blocks while the connection has more than
+    /// [write_high_water_mark](struct.Config.html#structfield.write_high_water_mark)
+    /// bytes left to write.
     pub fn send<C: NsqCmd>(&mut self, cmd: C) {
-        let cmd = cmd.as_cmd();
-        let _ = self.cmd_s.send(cmd);
-        let _ = self.sentinel.send(());
+        self.route.send(cmd.as_cmd());
+    }
+
+    /// Requeue a message delivered again after delay (REQ).
+    ///
+    /// Delays longer than [max_req_timeout](struct.Config.html#structfield.max_req_timeout)
+    /// are refused, nothing is sent.
+    pub fn requeue(&mut self, id: MessageId, delay: Duration) -> Result<(), DelayError> {
+        let req = Requeue(id, delay);
+        check_delay(&req, self.max_req_timeout)?;
+        self.send(req);
+        Ok(())
+    }
+
+    // answer nsqd for a handler.
+    pub(crate) fn dispose(&mut self, id: MessageId, attemps: u16, disposition: Disposition) {
+        if let Some(balancer) = self.balancer.as_ref() {
+            match disposition {
+                Disposition::Finish => balancer.success(),
+                Disposition::RequeueWithBackoff => balancer.failure(),
+                Disposition::Requeue(_) | Disposition::Touch => {}
+            }
+        }
+        match disposition {
+            Disposition::Finish => self.send(Fin(id)),
+            Disposition::Requeue(delay) => {
+                if let Err(e) = self.requeue(id, delay) {
+                    error!(
+                        "{}, message {} requeued after {}ms",
+                        e,
+                        id,
+                        e.max.as_millis()
+                    );
+                    self.send(Requeue(id, e.max));
+                }
+            }
+            Disposition::RequeueWithBackoff => {
+                let delay = self.requeue_delay * u32::from(attemps.max(1));
+                self.send(Requeue(id, delay.min(self.max_requeue_delay)));
+            }
+            Disposition::Touch => self.send(Touch(id)),
+        }
+    }
+}
+
+// host part of an nsqd address (ex. "nsqd:4150", "[::1]:4150").
+pub(crate) fn host(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(i) if !addr[i..].contains(']') => &addr[..i],
+        _ => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn check_read_timeout(last_read: Instant, read_timeout: Option<Duration>) -> io::Result<()> {
+    match read_timeout {
+        Some(timeout) if last_read.elapsed() > timeout => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "heartbeat not received from nsqd",
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{frame, read_body, NSQD_CONFIG};
+    use crate::publisher::tests::nsqd;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+
+    fn accept(_cmd: &str) -> Option<&'static str> {
+        None
+    }
+
+    // nsqd delivering two messages on the first RDY, returns the commands received.
+    fn consumer_nsqd(sent_s: Sender<()>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic).unwrap();
+            let mut cmds = Vec::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return cmds;
+                }
+                let cmd = line.trim_end().to_owned();
+                match cmd.as_str() {
+                    "IDENTIFY" => {
+                        read_body(&mut reader);
+                        writer.write_all(&frame(0, NSQD_CONFIG.as_bytes())).unwrap();
+                    }
+                    "SUB t c" => writer.write_all(&frame(0, b"OK")).unwrap(),
+                    "RDY 2" => {
+                        for id in [b"0000000000000001", b"0000000000000002"].iter() {
+                            let mut msg = vec![0; 10];
+                            msg[9] = 1;
+                            msg.extend_from_slice(&id[..]);
+                            msg.extend_from_slice(b"body");
+                            writer.write_all(&frame(2, &msg)).unwrap();
+                        }
+                        sent_s.send(()).unwrap();
+                    }
+                    "CLS" => writer.write_all(&frame(0, CLOSE_WAIT.as_bytes())).unwrap(),
+                    _ => {}
+                }
+                cmds.push(cmd);
+            }
+        });
+        (addr, handle)
+    }
+
+    // answer the handshake of a consumer with the IDENTIFY response given, subscribed_s is
+    // told once it sent RDY unless the connection is dropped right after it.
+    // Returns the commands received.
+    fn subscriber(
+        stream: TcpStream,
+        nsqd_config: &str,
+        subscribed_s: &Sender<()>,
+        drop: bool,
+    ) -> Vec<String> {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).unwrap();
+        let mut cmds = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return cmds;
+            }
+            let cmd = line.trim_end().to_owned();
+            match cmd.as_str() {
+                "IDENTIFY" => {
+                    read_body(&mut reader);
+                    writer.write_all(&frame(0, nsqd_config.as_bytes())).unwrap();
+                }
+                "AUTH" => {
+                    assert_eq!(read_body(&mut reader), b"secret");
+                    let resp = r#"{"identity":"client","permission_count":2}"#;
+                    writer.write_all(&frame(0, resp.as_bytes())).unwrap();
+                }
+                "SUB t c" => writer.write_all(&frame(0, b"OK")).unwrap(),
+                _ => {}
+            }
+            let rdy = cmd.starts_with("RDY");
+            cmds.push(cmd);
+            if rdy && drop {
+                return cmds;
+            }
+            if rdy {
+                let _ = subscribed_s.send(());
+            }
+        }
+    }
+
+    // nsqd dropping the first connection once subscribed, returns the commands of each
+    // connection.
+    fn flaky_nsqd(subscribed_s: Sender<()>) -> (String, JoinHandle<Vec<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            (0..2)
+                .map(|i| {
+                    let (stream, _) = listener.accept().unwrap();
+                    subscriber(stream, NSQD_CONFIG, &subscribed_s, i == 0)
+                })
+                .collect()
+        });
+        (addr, handle)
+    }
+
+    // nsqd listening on host and serving a single consumer connection.
+    fn subscriber_nsqd(
+        host: &str,
+        nsqd_config: String,
+        subscribed_s: Sender<()>,
+    ) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind((host, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            subscriber(stream, &nsqd_config, &subscribed_s, false)
+        });
+        (port, handle)
+    }
+
+    // nsqlookupd answering each lookup with the next nsqd ports on host, then going away.
+    fn lookupd(host: &'static str, lookups: Vec<Vec<u16>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for ports in lookups {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                }
+                let producers: Vec<String> = ports
+                    .iter()
+                    .map(|port| {
+                        format!(r#"{{"broadcast_address":"{}","tcp_port":{}}}"#, host, port)
+                    })
+                    .collect();
+                write!(
+                    stream,
+                    "HTTP/1.0 200 OK\r\n\r\n{{\"producers\":[{}]}}",
+                    producers.join(",")
+                )
+                .unwrap();
+            }
+        });
+        addr
+    }
+
+    fn consumer(addr: String, config: Config) -> (Client<String>, Receiver<ConnMsgInfo>) {
+        let (_in_s, in_r) = channel::unbounded();
+        let (info_s, info_r) = channel::unbounded();
+        let client = Client::new(
+            "t".to_owned(),
+            "c".to_owned(),
+            addr,
+            config,
+            None,
+            1,
+            5,
+            in_r,
+            info_s,
+        );
+        (client, info_r)
+    }
+
+    #[test]
+    fn reconnect_replays_handshake() {
+        let (subscribed_s, subscribed_r) = channel::unbounded();
+        let (addr, server) = flaky_nsqd(subscribed_s);
+        let (mut client, info_r) = consumer(addr.clone(), Config::new());
+        let handle = client.handle();
+        let run = thread::spawn(move || client.run());
+        subscribed_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.close();
+        run.join().unwrap().unwrap();
+        let cmds = server.join().unwrap();
+        assert_eq!(cmds[0], ["IDENTIFY", "SUB t c", "RDY 1"]);
+        assert_eq!(cmds[1], cmds[0]);
+        let infos: Vec<_> = info_r.try_iter().collect();
+        match &infos[..] {
+            [ConnMsgInfo::IsConnected(up), ConnMsgInfo::IsConnected(lost), ConnMsgInfo::Reconnect(reconnect), ConnMsgInfo::IsConnected(again)] =>
+            {
+                assert!(up.connected && !lost.connected && again.connected);
+                assert_eq!(reconnect.addr, addr);
+                assert_eq!(reconnect.attempt, 1);
+            }
+            _ => panic!("unexpected infos: {:?}", infos),
+        }
+    }
+
+    #[test]
+    fn reconnect_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // every connection is closed right away, the first one and 2 attempts.
+        let server = thread::spawn(move || {
+            for _ in 0..3 {
+                drop(listener.accept().unwrap());
+            }
+        });
+        let (mut client, info_r) = consumer(addr, Config::new().max_reconnect_attempts(2));
+        match client.run() {
+            Err(ConnError::IoError(_)) => {}
+            res => panic!("expected an io error, got {:?}", res),
+        }
+        server.join().unwrap();
+        let attempts: Vec<u32> = info_r
+            .try_iter()
+            .filter_map(|info| match info {
+                ConnMsgInfo::Reconnect(reconnect) => Some(reconnect.attempt),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(attempts, [1, 2]);
+    }
+
+    #[test]
+    fn close_during_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || drop(listener.accept().unwrap()));
+        let (mut client, info_r) = consumer(addr, Config::new());
+        let handle = client.handle();
+        let run = thread::spawn(move || client.run());
+        match info_r.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConnMsgInfo::IsConnected(conn) => assert!(!conn.connected),
+            info => panic!("unexpected info: {:?}", info),
+        }
+        // the first backoff is at least 250ms.
+        let start = Instant::now();
+        handle.close();
+        run.join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn sessions_follow_lookups() {
+        let (subscribed_s, subscribed_r) = channel::unbounded();
+        let (first, first_server) =
+            subscriber_nsqd("127.0.0.1", NSQD_CONFIG.to_owned(), subscribed_s.clone());
+        let (second, second_server) =
+            subscriber_nsqd("127.0.0.1", NSQD_CONFIG.to_owned(), subscribed_s);
+        // the first nsqd is found, then both, then only the second one.
+        let lookupd = lookupd(
+            "127.0.0.1",
+            vec![vec![first], vec![first, second], vec![second]],
+        );
+        let config = Config::new().lookupd_poll_interval(200);
+        let (mut client, _info_r) = consumer(String::new(), config);
+        client.add_lookupd(lookupd);
+        let handle = client.handle();
+        let run = thread::spawn(move || client.run());
+        // the first session is closed once nsqlookupd stops reporting its nsqd.
+        let first_cmds = first_server.join().unwrap();
+        assert_eq!(first_cmds[..2], ["IDENTIFY", "SUB t c"]);
+        subscribed_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        subscribed_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.connections() != 1 {
+            assert!(Instant::now() < deadline, "first session still connected");
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.close();
+        run.join().unwrap().unwrap();
+        let second_cmds = second_server.join().unwrap();
+        assert_eq!(second_cmds[..2], ["IDENTIFY", "SUB t c"]);
+    }
+
+    #[test]
+    fn ipv6_producer() {
+        let (subscribed_s, subscribed_r) = channel::unbounded();
+        let (port, server) = subscriber_nsqd("::1", NSQD_CONFIG.to_owned(), subscribed_s);
+        let lookupd = lookupd("::1", vec![vec![port]]);
+        let (mut client, _info_r) = consumer(String::new(), Config::new());
+        client.add_lookupd(lookupd);
+        let handle = client.handle();
+        let run = thread::spawn(move || client.run());
+        subscribed_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(handle.connections(), 1);
+        handle.close();
+        run.join().unwrap().unwrap();
+        let cmds = server.join().unwrap();
+        assert_eq!(cmds[..2], ["IDENTIFY", "SUB t c"]);
+    }
+
+    // nsqd answering IDENTIFY with its configuration changed by replace, then silent.
+    // Returns what was received after IDENTIFY once the client closes.
+    fn identify_nsqd(replace: (&str, &str)) -> (String, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let config = NSQD_CONFIG.replace(replace.0, replace.1);
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "IDENTIFY\n");
+            read_body(&mut reader);
+            writer.write_all(&frame(0, config.as_bytes())).unwrap();
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+            rest
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn auth_required_without_secret() {
+        let (addr, server) = identify_nsqd((r#""auth_required":false"#, r#""auth_required":true"#));
+        let (mut client, _info_r) = consumer(addr, Config::new());
+        match client.run() {
+            Err(ConnError::AuthRequired) => {}
+            res => panic!("expected AuthRequired, got {:?}", res),
+        }
+        // nothing is sent after IDENTIFY.
+        assert!(server.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn auth_info() {
+        let (subscribed_s, subscribed_r) = channel::unbounded();
+        let nsqd_config =
+            NSQD_CONFIG.replace(r#""auth_required":false"#, r#""auth_required":true"#);
+        let (port, server) = subscriber_nsqd("127.0.0.1", nsqd_config, subscribed_s);
+        let addr = format!("127.0.0.1:{}", port);
+        let (_in_s, in_r) = channel::unbounded();
+        let (info_s, info_r) = channel::unbounded();
+        let mut client = Client::new(
+            "t".to_owned(),
+            "c".to_owned(),
+            addr.clone(),
+            Config::new(),
+            Some("secret".to_owned()),
+            1,
+            5,
+            in_r,
+            info_s,
+        );
+        let handle = client.handle();
+        let run = thread::spawn(move || client.run());
+        subscribed_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.close();
+        run.join().unwrap().unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            ["IDENTIFY", "AUTH", "SUB t c", "RDY 1"]
+        );
+        let auth = info_r
+            .try_iter()
+            .find_map(|info| match info {
+                ConnMsgInfo::Auth(auth) => Some(auth),
+                _ => None,
+            })
+            .expect("no auth info sent");
+        assert_eq!(auth.addr, addr);
+        assert_eq!(auth.resp.identity, "client");
+        assert_eq!(auth.resp.permission_count, 2);
+    }
+
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    #[test]
+    fn tls_handshake_timeout() {
+        let (addr, server) = identify_nsqd((r#""tls_v1":false"#, r#""tls_v1":true"#));
+        let mut config = Config::new()
+            .tls_handshake_timeout(200)
+            .max_reconnect_attempts(0);
+        config.tls();
+        let (mut client, _info_r) = consumer(addr, config);
+        let start = Instant::now();
+        match client.run() {
+            Err(ConnError::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // the ClientHello nsqd never answered.
+        assert!(!server.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unresolvable_nsqd() {
+        let config = Config::new().max_reconnect_attempts(0);
+        let (mut client, _info_r) = consumer("nsqd.invalid:4150".to_owned(), config);
+        match client.run() {
+            Err(ConnError::IoError(_)) => {}
+            res => panic!("expected an io error, got {:?}", res),
+        }
+    }
+
+    #[derive(Clone)]
+    struct Slow {
+        wait: Duration,
+        handled: Arc<AtomicUsize>,
+        // messages handled when on_close is called.
+        closed: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Handler for Slow {
+        type Outcome = Disposition;
+
+        fn handle(&mut self, _msg: Msg, _ctx: &mut Context) -> Disposition {
+            thread::sleep(self.wait);
+            self.handled.fetch_add(1, Ordering::SeqCst);
+            Disposition::Finish
+        }
+
+        fn on_close(&mut self, _ctx: &mut Context) {
+            let handled = self.handled.load(Ordering::SeqCst);
+            self.closed.lock().unwrap().push(handled);
+        }
+    }
+
+    // drain a consumer whose handlers take wait per message.
+    fn drain(wait: Duration, drain_timeout: u64) -> (Vec<String>, Vec<usize>) {
+        let (sent_s, sent_r) = channel::unbounded();
+        let (addr, server) = consumer_nsqd(sent_s);
+        let (_in_s, in_r) = channel::unbounded();
+        let (info_s, _info_r) = channel::unbounded();
+        let config = Config::new().drain_timeout(drain_timeout);
+        let mut client = Client::new(
+            "t".to_owned(),
+            "c".to_owned(),
+            addr,
+            config,
+            None,
+            2,
+            5,
+            in_r,
+            info_s,
+        );
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let slow = Slow {
+            wait,
+            handled: Arc::new(AtomicUsize::new(0)),
+            closed: closed.clone(),
+        };
+        client.spawn_handler(2, slow);
+        let handle = client.handle();
+        let run = thread::spawn(move || client.run());
+        sent_r.recv().unwrap();
+        handle.drain();
+        run.join().unwrap().unwrap();
+        let cmds = server.join().unwrap();
+        while closed.lock().unwrap().len() < 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let closed = closed.lock().unwrap().clone();
+        (cmds, closed)
+    }
+
+    #[test]
+    fn drain_in_flight() {
+        let (cmds, closed) = drain(Duration::from_millis(200), 5000);
+        assert_eq!(cmds[..4], ["IDENTIFY", "SUB t c", "RDY 2", "RDY 0"]);
+        assert_eq!(cmds[4], "CLS");
+        let mut fins = cmds[5..].to_vec();
+        fins.sort();
+        assert_eq!(fins, ["FIN 0000000000000001", "FIN 0000000000000002"]);
+        assert_eq!(closed, [2, 2]);
+    }
+
+    #[test]
+    fn drain_timeout() {
+        let (cmds, _) = drain(Duration::from_millis(500), 100);
+        assert_eq!(cmds, ["IDENTIFY", "SUB t c", "RDY 2", "RDY 0", "CLS"]);
+    }
+
+    #[test]
+    fn clients_close_alone() {
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let (addr, server) = nsqd(1, accept);
+                let (_in_s, in_r) = channel::unbounded();
+                let (info_s, _info_r) = channel::unbounded();
+                let mut client = Client::new(
+                    String::new(),
+                    String::new(),
+                    addr,
+                    Config::new(),
+                    None,
+                    1,
+                    1,
+                    in_r,
+                    info_s,
+                );
+                let handle = client.handle();
+                let run = thread::spawn(move || client.run());
+                while !handle.is_connected() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                (handle, run, server)
+            })
+            .collect();
+        let (handle, _, _) = &handles[0];
+        handle.close();
+        while !handle.is_closed() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(handle.connections(), 0);
+        let (other, _, _) = &handles[1];
+        assert!(!other.is_closed());
+        assert_eq!(other.connections(), 1);
+        for (handle, run, server) in handles {
+            handle.close();
+            run.join().unwrap().unwrap();
+            server.join().unwrap();
+        }
     }
 }
@@ -21,13 +21,13 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
 use std::str;
 
-use byteorder::{BigEndian, ByteOrder};
-use log::error;
+use crate::msgs::{Cmd, MessageId, MPUB};
 
-pub const HEADER_LENGTH: usize = 8;
+use byteorder::{BigEndian, ByteOrder};
 
 // Frame Types
 pub const FRAME_TYPE_RESPONSE: i32 = 0x00;
@@ -35,6 +35,8 @@ pub const FRAME_TYPE_ERROR: i32 = 0x01;
 pub const FRAME_TYPE_MESSAGE: i32 = 0x02;
 
 pub const HEARTBEAT: &str = "_heartbeat_";
+// response to CLS.
+pub const CLOSE_WAIT: &str = "CLOSE_WAIT";
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Response {
@@ -42,19 +44,115 @@ pub enum Response {
     Error(String),
 }
 
-pub fn decode_msg(buf: &mut BytesMut) -> (i64, u16, String, Vec<u8>) {
+// timestamp, attempts and id of a message frame.
+const MESSAGE_HEADER_LENGTH: usize = 26;
+// nsqd default --max-msg-size.
+const DEFAULT_MAX_MSG_SIZE: usize = 1048576;
+
+/// Frame read from nsqd.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Frame {
+    Response(String),
+    Error(String),
+    /// Message frame, read by [decode_msg](fn.decode_msg.html).
+    Message(BytesMut),
+}
+
+/// Split the bytes read from nsqd into frames, whatever the chunks they come in.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+    // largest size announced by a frame, its own 4 bytes excluded.
+    max_size: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    /// Refuse the frames of messages bigger than nsqd `--max-msg-size`.
+    pub fn max_msg_size(mut self, size: usize) -> FrameDecoder {
+        self.max_size = size.saturating_add(4 + MESSAGE_HEADER_LENGTH);
+        self
+    }
+
+    /// Add bytes read from nsqd.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Bytes not decoded yet, decompressed data is appended there.
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    /// Take the bytes not decoded yet.
+    pub fn take(&mut self) -> BytesMut {
+        self.buf.take()
+    }
+
+    /// Next frame, None until its last byte is read.
+    pub fn decode(&mut self) -> io::Result<Option<Frame>> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        // the size doesn't count its own 4 bytes.
+        let size = BigEndian::read_u32(&self.buf[..4]) as usize;
+        if size < 4 {
+            return Err(invalid_frame(format!("invalid frame size {}", size)));
+        }
+        if size > self.max_size {
+            return Err(invalid_frame(format!(
+                "frame size {} above the maximum of {}",
+                size, self.max_size
+            )));
+        }
+        if self.buf.len() < size + 4 {
+            self.buf.reserve(size + 4 - self.buf.len());
+            return Ok(None);
+        }
+        let _ = self.buf.split_to(4);
+        let mut frame = self.buf.split_to(size);
+        let frame_type = BigEndian::read_i32(&frame.split_to(4));
+        let frame = match frame_type {
+            FRAME_TYPE_RESPONSE => Frame::Response(String::from_utf8_lossy(&frame).into_owned()),
+            FRAME_TYPE_ERROR => Frame::Error(String::from_utf8_lossy(&frame).into_owned()),
+            FRAME_TYPE_MESSAGE if frame.len() >= MESSAGE_HEADER_LENGTH => Frame::Message(frame),
+            FRAME_TYPE_MESSAGE => {
+                return Err(invalid_frame(format!(
+                    "message frame of {} bytes",
+                    frame.len()
+                )))
+            }
+            _ => return Err(invalid_frame(format!("unknown frame type {}", frame_type))),
+        };
+        Ok(Some(frame))
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> FrameDecoder {
+        FrameDecoder {
+            buf: BytesMut::new(),
+            max_size: 0,
+        }
+        .max_msg_size(DEFAULT_MAX_MSG_SIZE)
+    }
+}
+
+fn invalid_frame(desc: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, desc)
+}
+
+pub fn decode_msg(buf: &mut BytesMut) -> (i64, u16, MessageId, Bytes) {
     // skip size and frame type
     let timestamp = BigEndian::read_i64(&buf.split_to(8)[..]);
     let attemps = BigEndian::read_u16(&buf.split_to(2)[..]);
-    let id_bytes = &buf.split_to(16)[..];
-    let id = match str::from_utf8(id_bytes) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("error deconding utf8 id: {}", e);
-            ""
-        }
-    };
-    (timestamp, attemps, id.to_owned(), Vec::from(&buf[..]))
+    let mut id = [0; 16];
+    id.copy_from_slice(&buf.split_to(16)[..]);
+    // the body keeps the memory of the frame.
+    (timestamp, attemps, MessageId(id), buf.take().freeze())
 }
 
 fn write_n(buf: &mut BytesMut) {
@@ -94,6 +192,22 @@ pub fn write_msg(buf: &mut BytesMut, msg: Vec<u8>) {
 }
 
 /// write multiple messages (aka msub command).
+// command line followed by its body, more bodies are framed as a batch.
+pub fn encode_cmd(buf: &mut BytesMut, cmd: Cmd) {
+    write_cmd(buf, &cmd.cmd);
+    let mut msgs = cmd.msg;
+    // MPUB keeps its framing with a single message.
+    if cmd.cmd.starts_with(MPUB) {
+        write_mmsg(buf, msgs);
+        return;
+    }
+    match msgs.len() {
+        0 => {}
+        1 => write_msg(buf, msgs.remove(0)),
+        _ => write_mmsg(buf, msgs),
+    }
+}
+
 pub fn write_mmsg(buf: &mut BytesMut, msgs: Vec<Vec<u8>>) {
     let len = msgs.len();
     let body_size = (msgs.iter().fold(0, |sum, e| sum + (e.len() + 4) as i32) + 4) as usize;
@@ -104,3 +218,134 @@ pub fn write_mmsg(buf: &mut BytesMut, msgs: Vec<Vec<u8>>) {
         write_msg(buf, msg);
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // IDENTIFY response of nsqd.
+    pub(crate) const NSQD_CONFIG: &str = r#"{"max_rdy_count":2500,"version":"1.2.0","max_msg_timeout":900000,"msg_timeout":60000,"tls_v1":false,"deflate":false,"deflate_level":6,"max_deflate_level":6,"snappy":false,"sample_rate":0,"auth_required":false,"output_buffer_size":16384,"output_buffer_timeout":250}"#;
+
+    // frame as nsqd writes it.
+    pub(crate) fn frame(frame_type: i32, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(data.len() as i32 + 4).to_be_bytes());
+        buf.extend_from_slice(&frame_type.to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    // body of a command as nsqd reads it.
+    pub(crate) fn read_body(reader: &mut impl Read) -> Vec<u8> {
+        let mut size = [0; 4];
+        reader.read_exact(&mut size).unwrap();
+        let mut body = vec![0; i32::from_be_bytes(size) as usize];
+        reader.read_exact(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn decode_message() {
+        let mut frame = BytesMut::new();
+        frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 7, 0, 3]);
+        frame.extend_from_slice(b"0000000000000001body");
+        let (timestamp, attemps, id, body) = decode_msg(&mut frame);
+        assert_eq!((timestamp, attemps), (7, 3));
+        assert_eq!(id, "0000000000000001".parse().unwrap());
+        assert_eq!(id.to_string(), "0000000000000001");
+        assert_eq!(&body[..], b"body");
+        assert!("1".parse::<MessageId>().is_err());
+    }
+
+    #[test]
+    fn encode_dpub() {
+        use crate::msgs::{Dpub, NsqCmd};
+        use std::time::Duration;
+
+        let mut buf = BytesMut::new();
+        let dpub = Dpub(
+            "t".to_owned(),
+            Duration::from_millis(1500),
+            b"body".to_vec(),
+        );
+        encode_cmd(&mut buf, dpub.as_cmd());
+        assert_eq!(&buf[..], &b"DPUB t 1500\n\x00\x00\x00\x04body"[..]);
+
+        buf.clear();
+        let dpub = Dpub("t".to_owned(), Duration::from_secs(0), b"now".to_vec());
+        assert_eq!(dpub.delay(), Some(Duration::from_secs(0)));
+        encode_cmd(&mut buf, dpub.as_cmd());
+        assert_eq!(&buf[..], &b"DPUB t 0\n\x00\x00\x00\x03now"[..]);
+    }
+
+    fn stream() -> (Vec<u8>, Vec<Frame>) {
+        let mut msg = vec![0; 10];
+        msg.extend_from_slice(b"0000000000000001body");
+        let mut bytes = frame(FRAME_TYPE_RESPONSE, b"OK");
+        bytes.extend(frame(FRAME_TYPE_MESSAGE, &msg));
+        bytes.extend(frame(FRAME_TYPE_RESPONSE, HEARTBEAT.as_bytes()));
+        bytes.extend(frame(FRAME_TYPE_ERROR, b"E_INVALID \xff"));
+        let frames = vec![
+            Frame::Response("OK".to_owned()),
+            Frame::Message(BytesMut::from(msg)),
+            Frame::Response(HEARTBEAT.to_owned()),
+            Frame::Error("E_INVALID \u{fffd}".to_owned()),
+        ];
+        (bytes, frames)
+    }
+
+    // decode the bytes read in chunks of size.
+    fn decode_chunks(bytes: &[u8], size: usize) -> Vec<Frame> {
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for chunk in bytes.chunks(size) {
+            decoder.extend(chunk);
+            while let Some(frame) = decoder.decode().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert!(decoder.take().is_empty());
+        frames
+    }
+
+    #[test]
+    fn frames_in_one_read() {
+        let (bytes, frames) = stream();
+        assert_eq!(decode_chunks(&bytes, bytes.len()), frames);
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let (bytes, frames) = stream();
+        for size in 1..bytes.len() {
+            assert_eq!(decode_chunks(&bytes, size), frames);
+        }
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&[0, 0, 0, 2, 0, 0]);
+        assert!(decoder.decode().is_err());
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&frame(7, b"OK"));
+        assert!(decoder.decode().is_err());
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&frame(FRAME_TYPE_MESSAGE, b"short"));
+        assert!(decoder.decode().is_err());
+        // refused from the size alone, nothing is reserved for it.
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(decoder.decode().is_err());
+        assert!(decoder.buffer_mut().capacity() < 1024);
+        let mut msg = vec![0; MESSAGE_HEADER_LENGTH];
+        msg.extend_from_slice(b"body");
+        let mut decoder = FrameDecoder::new().max_msg_size(3);
+        decoder.extend(&frame(FRAME_TYPE_MESSAGE, &msg));
+        assert!(decoder.decode().is_err());
+        let mut decoder = FrameDecoder::new().max_msg_size(4);
+        decoder.extend(&frame(FRAME_TYPE_MESSAGE, &msg));
+        assert!(decoder.decode().unwrap().is_some());
+    }
+}
@@ -0,0 +1,487 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use bytes::BytesMut;
+use flate2::{Compress, Compression as Level, Decompress, FlushCompress, FlushDecompress};
+use snap::read::FrameDecoder;
+use snap::write::FrameEncoder;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+// snappy framing format chunk header: 1 byte type, 3 bytes little endian length.
+const SNAPPY_CHUNK_HEADER: usize = 4;
+// output room added on every deflate call.
+const DEFLATE_CHUNK: usize = 4096;
+
+/// Stream compression negotiated with IDENTIFY.
+///
+/// Once nsqd confirms it every byte written to and read from the socket goes through it.
+pub enum Compression {
+    Snappy(Box<Snappy>),
+    Deflate(Deflate),
+}
+
+impl Compression {
+    /// Compress the bytes about to be written on the socket.
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Snappy(s) => s.compress(data),
+            Compression::Deflate(d) => d.compress(data),
+        }
+    }
+
+    /// Decompress the bytes read from the socket into `out`, returns the decompressed size.
+    ///
+    /// Incomplete chunks are kept until the rest of them is read.
+    pub fn decompress(&mut self, data: &[u8], out: &mut BytesMut) -> io::Result<usize> {
+        match self {
+            Compression::Snappy(s) => s.decompress(data, out),
+            Compression::Deflate(d) => d.decompress(data, out),
+        }
+    }
+}
+
+impl std::fmt::Debug for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Compression::Snappy(_) => write!(f, "Snappy"),
+            Compression::Deflate(_) => write!(f, "Deflate"),
+        }
+    }
+}
+
+/// Snappy framing format, as used by nsqd.
+pub struct Snappy {
+    encoder: FrameEncoder<Vec<u8>>,
+    // only complete chunks are handed to the decoder, it can't resume a partial read.
+    decoder: FrameDecoder<VecDeque<u8>>,
+    pending: BytesMut,
+}
+
+impl Snappy {
+    pub fn new() -> Snappy {
+        Snappy {
+            encoder: FrameEncoder::new(Vec::new()),
+            decoder: FrameDecoder::new(VecDeque::new()),
+            pending: BytesMut::new(),
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.encoder.write_all(data)?;
+        self.encoder.flush()?;
+        Ok(std::mem::take(self.encoder.get_mut()))
+    }
+
+    fn decompress(&mut self, data: &[u8], out: &mut BytesMut) -> io::Result<usize> {
+        self.pending.extend_from_slice(data);
+        loop {
+            if self.pending.len() < SNAPPY_CHUNK_HEADER {
+                break;
+            }
+            let len = self.pending[1] as usize
+                | (self.pending[2] as usize) << 8
+                | (self.pending[3] as usize) << 16;
+            if self.pending.len() < SNAPPY_CHUNK_HEADER + len {
+                break;
+            }
+            let chunk = self.pending.split_to(SNAPPY_CHUNK_HEADER + len);
+            self.decoder.get_mut().extend(chunk.iter());
+        }
+        let mut buf = Vec::new();
+        self.decoder.read_to_end(&mut buf)?;
+        out.extend_from_slice(&buf);
+        Ok(buf.len())
+    }
+}
+
+impl Default for Snappy {
+    fn default() -> Snappy {
+        Snappy::new()
+    }
+}
+
+/// Raw deflate (no zlib header), every write is sync flushed as nsqd does.
+pub struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl Deflate {
+    pub fn new(level: u32) -> Deflate {
+        Deflate {
+            compress: Compress::new(Level::new(level), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() + DEFLATE_CHUNK);
+        let mut input = data;
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(DEFLATE_CHUNK);
+            }
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut out, FlushCompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            input = &input[(self.compress.total_in() - before) as usize..];
+            // the flush is complete once the output stops filling the buffer.
+            if input.is_empty() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+        }
+    }
+
+    fn decompress(&mut self, data: &[u8], out: &mut BytesMut) -> io::Result<usize> {
+        let mut buf = Vec::with_capacity(data.len() * 2 + DEFLATE_CHUNK);
+        let mut input = data;
+        loop {
+            if buf.len() == buf.capacity() {
+                buf.reserve(DEFLATE_CHUNK);
+            }
+            let before = self.decompress.total_in();
+            self.decompress
+                .decompress_vec(input, &mut buf, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            input = &input[(self.decompress.total_in() - before) as usize..];
+            if input.is_empty() && buf.len() < buf.capacity() {
+                break;
+            }
+        }
+        out.extend_from_slice(&buf);
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Route;
+    use crate::codec::tests::{frame, read_body, NSQD_CONFIG};
+    use crate::codec::Response;
+    use crate::config::{Config, NsqdConfig};
+    use crate::conn::Conn;
+    use crate::state::{Handshake, Magic, Params, State};
+    use crossbeam::channel;
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+    use mio::Registration;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // nsqd switching to snappy or deflate after IDENTIFY, it answers SUB and RDY with a
+    // message and returns the commands received once compressed.
+    fn nsqd(snappy: bool) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "IDENTIFY\n");
+            let identify: serde_json::Value =
+                serde_json::from_slice(&read_body(&mut reader)).unwrap();
+            assert_eq!(identify["snappy"], snappy);
+            assert_eq!(identify["deflate"], !snappy);
+            if !snappy {
+                assert_eq!(identify["deflate_level"], 9);
+            }
+            let resp = NSQD_CONFIG
+                .replace(r#""deflate":false"#, &format!(r#""deflate":{}"#, !snappy))
+                .replace(r#""snappy":false"#, &format!(r#""snappy":{}"#, snappy))
+                .replace(r#"deflate_level":6"#, r#"deflate_level":5"#);
+            stream.write_all(&frame(0, resp.as_bytes())).unwrap();
+            let (mut w, r): (Box<dyn Write>, Box<dyn Read>) = if snappy {
+                (
+                    Box::new(FrameEncoder::new(stream)),
+                    Box::new(FrameDecoder::new(reader)),
+                )
+            } else {
+                (
+                    Box::new(DeflateEncoder::new(stream, flate2::Compression::new(5))),
+                    Box::new(DeflateDecoder::new(reader)),
+                )
+            };
+            w.write_all(&frame(0, b"OK")).unwrap();
+            w.flush().unwrap();
+            let mut r = BufReader::new(r);
+            let mut cmds = Vec::new();
+            loop {
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                cmds.push(line.trim_end().to_owned());
+                if line.starts_with("SUB") {
+                    w.write_all(&frame(0, b"OK")).unwrap();
+                } else if line.starts_with("RDY") {
+                    let mut msg = Vec::new();
+                    msg.extend_from_slice(&0i64.to_be_bytes());
+                    msg.extend_from_slice(&1u16.to_be_bytes());
+                    msg.extend_from_slice(b"0000000000000001");
+                    msg.extend_from_slice(&b"compressed body ".repeat(100));
+                    w.write_all(&frame(2, &msg)).unwrap();
+                } else if line.starts_with("FIN") {
+                    return cmds;
+                }
+                w.flush().unwrap();
+            }
+        });
+        (addr, handle)
+    }
+
+    fn read_response(conn: &mut Conn, stream: &mut TcpStream) -> String {
+        while conn.responses.is_empty() {
+            conn.read(stream).unwrap();
+        }
+        match conn.responses.pop().unwrap() {
+            Response::Response(r) => r,
+            Response::Error(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn snappy_round_trip() {
+        let mut client = Snappy::new();
+        let mut server = Snappy::new();
+        let mut out = BytesMut::new();
+        for i in 0..20 {
+            let data = format!("PUB topic\n{}", "x".repeat(i * 100));
+            let compressed = client.compress(data.as_bytes()).unwrap();
+            // chunks are decoded once complete, whatever the reads they are split across.
+            let split = (i * 7) % compressed.len();
+            let (a, b) = compressed.split_at(split);
+            out.clear();
+            server.decompress(a, &mut out).unwrap();
+            server.decompress(b, &mut out).unwrap();
+            assert_eq!(out.as_ref(), data.as_bytes());
+        }
+        // a chunk header split in the middle.
+        let compressed = client.compress(b"FIN 0000000000000001\n").unwrap();
+        out.clear();
+        for byte in compressed.iter() {
+            server.decompress(&[*byte], &mut out).unwrap();
+        }
+        assert_eq!(out.as_ref(), b"FIN 0000000000000001\n");
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let mut client = Deflate::new(6);
+        let mut server = Deflate::new(6);
+        let mut out = BytesMut::new();
+        for i in 0..20 {
+            let data = format!("PUB topic\n{}", "x".repeat(i * 100));
+            let compressed = client.compress(data.as_bytes()).unwrap();
+            // sync flush: everything written so far can be decompressed, even split in two.
+            let (a, b) = compressed.split_at(compressed.len() / 2);
+            out.clear();
+            server.decompress(a, &mut out).unwrap();
+            server.decompress(b, &mut out).unwrap();
+            assert_eq!(out.as_ref(), data.as_bytes());
+        }
+    }
+
+    // go through the handshake with nsqd once the stream is compressed, FIN the message received.
+    fn consume(config: Config) -> NsqdConfig {
+        let snappy = config.snappy;
+        let (addr, handle) = nsqd(snappy);
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        let (cmd_s, cmd_r) = channel::unbounded();
+        let (msg_s, msg_r) = channel::unbounded();
+        let (info_s, _info_r) = channel::unbounded();
+        let (_reg, waker) = Registration::new2();
+        let params = Params {
+            addr: addr.clone(),
+            config: config.clone(),
+            topic: "t".to_owned(),
+            channel: "c".to_owned(),
+            secret: None,
+        };
+        let mut conn = Conn::new(config, cmd_r, msg_s, info_s, Route::new(cmd_s, waker), 0);
+        let identify = Magic::new(params).identify(conn.w_buf()).unwrap();
+        conn.write(&mut stream).unwrap();
+        let resp = read_response(&mut conn, &mut stream);
+        let compress = match identify.on_response(&resp, conn.w_buf()).unwrap() {
+            Handshake::Compress(compress) => compress,
+            _ => panic!("compression not negotiated"),
+        };
+        let (compression, state) = compress.enable();
+        match compression {
+            Compression::Snappy(_) => assert!(snappy),
+            Compression::Deflate(_) => assert!(!snappy),
+        }
+        conn.compress(compression).unwrap();
+        let resp = read_response(&mut conn, &mut stream);
+        assert_eq!(resp, "OK");
+        let state = match state.on_response(&resp, conn.w_buf()).unwrap() {
+            Handshake::Waiting(state) => state,
+            _ => panic!("SUB not sent"),
+        };
+        conn.write(&mut stream).unwrap();
+        let resp = read_response(&mut conn, &mut stream);
+        let done = match state.on_response(&resp, conn.w_buf()).unwrap() {
+            Handshake::Rdy(rdy) => rdy.rdy(1, conn.w_buf()),
+            _ => panic!("SUB not answered"),
+        };
+        conn.started();
+        conn.write(&mut stream).unwrap();
+        while conn.in_flight() == 0 {
+            conn.read(&mut stream).unwrap();
+        }
+        let mut msg = msg_r.recv().unwrap();
+        let (_, attempts, id, body) = crate::codec::decode_msg(&mut msg.1);
+        assert_eq!(attempts, 1);
+        assert_eq!(body, b"compressed body ".repeat(100));
+        conn.write_cmd(crate::msgs::Fin(id));
+        conn.write(&mut stream).unwrap();
+        assert_eq!(
+            handle.join().unwrap(),
+            vec!["SUB t c", "RDY 1", "FIN 0000000000000001"]
+        );
+        done.nsqd_config().clone()
+    }
+
+    #[test]
+    fn deflate_nsqd() {
+        let config = Config::new().snappy(true).deflate(true).deflate_level(12);
+        assert!(!config.snappy);
+        assert!(consume(config).deflate);
+    }
+
+    #[test]
+    fn snappy_nsqd() {
+        let config = Config::new().deflate(true).snappy(true);
+        assert!(!config.deflate);
+        assert!(consume(config).snappy);
+    }
+
+    // read and write halves of a tls stream.
+    #[cfg(feature = "native-tls")]
+    struct Shared<S>(std::rc::Rc<std::cell::RefCell<S>>);
+
+    #[cfg(feature = "native-tls")]
+    impl<S: Read> Read for Shared<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.borrow_mut().read(buf)
+        }
+    }
+
+    #[cfg(feature = "native-tls")]
+    impl<S: Write> Write for Shared<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.borrow_mut().flush()
+        }
+    }
+
+    // nsqd upgrading to tls then to deflate after IDENTIFY, as nsqd does.
+    // Returns the commands received once compressed.
+    #[cfg(feature = "native-tls")]
+    fn tls_deflate_nsqd() -> (String, thread::JoinHandle<Vec<String>>) {
+        use native_tls::{Identity, TlsAcceptor};
+
+        let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+        let cert = std::fs::read(format!("{}/server.pem", certs)).unwrap();
+        let key = std::fs::read(format!("{}/server.key", certs)).unwrap();
+        let acceptor = TlsAcceptor::new(Identity::from_pkcs8(&cert, &key).unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut magic = [0; 4];
+            stream.read_exact(&mut magic).unwrap();
+            let mut line = [0; 9];
+            stream.read_exact(&mut line).unwrap();
+            assert_eq!(&line, b"IDENTIFY\n");
+            let identify: serde_json::Value =
+                serde_json::from_slice(&read_body(&mut stream)).unwrap();
+            assert_eq!(identify["tls_v1"], true);
+            assert_eq!(identify["deflate"], true);
+            let resp = NSQD_CONFIG
+                .replace(r#""tls_v1":false"#, r#""tls_v1":true"#)
+                .replace(r#""deflate":false"#, r#""deflate":true"#);
+            stream.write_all(&frame(0, resp.as_bytes())).unwrap();
+            // the handshake fails unless the client upgrades first.
+            let mut tls = acceptor.accept(stream).unwrap();
+            tls.write_all(&frame(0, b"OK")).unwrap();
+            let tls = std::rc::Rc::new(std::cell::RefCell::new(tls));
+            let mut w = DeflateEncoder::new(Shared(tls.clone()), flate2::Compression::new(6));
+            w.write_all(&frame(0, b"OK")).unwrap();
+            w.flush().unwrap();
+            let mut r = BufReader::new(DeflateDecoder::new(Shared(tls)));
+            let mut cmds = Vec::new();
+            while cmds.len() < 2 {
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                if line.starts_with("SUB") {
+                    w.write_all(&frame(0, b"OK")).unwrap();
+                    w.flush().unwrap();
+                }
+                cmds.push(line.trim_end().to_owned());
+            }
+            cmds
+        });
+        (addr, handle)
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn tls_then_deflate() {
+        use crate::client::Client;
+        use crate::config::VerifyServerCert;
+
+        let (addr, server) = tls_deflate_nsqd();
+        let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/ca.pem");
+        let mut config = Config::new()
+            .deflate(true)
+            .verify_server_cert(VerifyServerCert::PrivateCA(ca.to_owned()))
+            .tls_server_name("localhost");
+        config.tls();
+        let (_in_s, in_r) = channel::unbounded();
+        let (info_s, _info_r) = channel::unbounded();
+        let mut client = Client::new(
+            "t".to_owned(),
+            "c".to_owned(),
+            addr,
+            config,
+            None,
+            1,
+            5,
+            in_r,
+            info_s,
+        );
+        let handle = client.handle();
+        let run = thread::spawn(move || client.run());
+        assert_eq!(server.join().unwrap(), ["SUB t c", "RDY 1"]);
+        handle.close();
+        run.join().unwrap().unwrap();
+    }
+}
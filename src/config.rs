@@ -67,25 +67,27 @@ pub struct Config {
 
     /// Enable TLS negotiation
     ///
-    /// Default: **false** (Not implemented)
+    /// Default: **false**
     tls_v1: bool,
 
     /// Enable snappy compression.
     ///
-    /// Default: **false** (Not implemented)
+    /// Default: **false**
     pub snappy: bool,
 
     /// Enable deflate compression.
     ///
-    /// Default: **false** (Not implemented)
-    deflate: bool,
+    /// Default: **false**
+    pub deflate: bool,
     /// Configure deflate compression level.
     ///
     /// Valid range:
     /// * 1 <= deflate_level <= configured_max
     ///
+    /// The level is lowered to the max_deflate_level reported by nsqd.
+    ///
     /// Default: **6**
-    deflate_level: u16,
+    pub deflate_level: u16,
 
     /// Integer percentage to sample the channel.
     ///
@@ -103,6 +105,169 @@ pub struct Config {
     ///
     /// Default: **0**
     pub message_timeout: u32,
+
+    /// Maximum number of consecutive reconnection attempts before giving up.
+    ///
+    /// The counter is reset every time a connection completes the handshake,
+    /// `None` keeps reconnecting forever.
+    ///
+    /// Default: **None**
+    #[serde(skip)]
+    pub max_reconnect_attempts: Option<u32>,
+
+    /// Duration of time between nsqlookupd queries (milliseconds).
+    ///
+    /// Default: **60000**
+    #[serde(skip)]
+    pub lookupd_poll_interval: u64,
+
+    /// TLS implementation used when nsqd enables TLS.
+    ///
+    /// Default: **NativeTls**, **Rustls** when only the `rustls` feature is enabled
+    #[serde(skip)]
+    pub tls_backend: TlsBackend,
+
+    /// How the nsqd certificate is verified.
+    ///
+    /// Default: **PublicCA**
+    #[serde(skip)]
+    pub verify_server_cert: VerifyServerCert,
+
+    /// Name used for SNI and certificate verification.
+    ///
+    /// Default: **host** of the nsqd address
+    #[serde(skip)]
+    pub tls_server_name: Option<String>,
+
+    /// Client certificate (PEM file) presented to nsqd, needed by `tls_client_auth_policy`.
+    ///
+    /// Default: **None**
+    #[serde(skip)]
+    pub tls_client_cert: Option<String>,
+
+    /// Private key (PKCS#8 PEM file) of the client certificate.
+    ///
+    /// Default: **None**
+    #[serde(skip)]
+    pub tls_client_key: Option<String>,
+
+    /// Maximum duration of the TLS handshake (milliseconds).
+    ///
+    /// Default: **10000**
+    #[serde(skip)]
+    pub tls_handshake_timeout: u64,
+
+    /// Number of messages of a [Batcher](struct.Batcher.html) batch which triggers its MPUB.
+    ///
+    /// Default: **100**
+    #[serde(skip)]
+    pub batch_max_count: usize,
+
+    /// Size in bytes of a [Batcher](struct.Batcher.html) batch which triggers its MPUB.
+    ///
+    /// Default: **65536**
+    #[serde(skip)]
+    pub batch_max_size: usize,
+
+    /// Time in milliseconds a [Batcher](struct.Batcher.html) batch waits for more
+    /// messages before its MPUB.
+    ///
+    /// Default: **10**
+    #[serde(skip)]
+    pub batch_linger: u64,
+
+    /// Maximum size in bytes of a PUB or MPUB body accepted by nsqd (nsqd `--max-body-size`),
+    /// batches are published before growing past it.
+    ///
+    /// Default: **5242880**
+    #[serde(skip)]
+    pub max_body_size: usize,
+
+    /// Maximum delay in milliseconds of a DPUB or REQ accepted by nsqd (nsqd
+    /// `--max-req-timeout`), longer delays are refused before being sent.
+    ///
+    /// nsqd doesn't give it in the IDENTIFY response, it has to match its configuration.
+    ///
+    /// Default: **3600000**
+    #[serde(skip)]
+    pub max_req_timeout: u64,
+
+    /// Delay in milliseconds of a message requeued with backoff by a
+    /// [Handler](trait.Handler.html), multiplied by its attempts.
+    ///
+    /// Default: **90000**
+    #[serde(skip)]
+    pub requeue_delay: u64,
+
+    /// Maximum delay in milliseconds of a message requeued with backoff.
+    ///
+    /// Default: **900000**
+    #[serde(skip)]
+    pub max_requeue_delay: u64,
+
+    /// Unit in milliseconds of the consumer backoff after handler failures,
+    /// the wait is `backoff_multiplier * 2^failures`.
+    ///
+    /// Default: **1000**
+    #[serde(skip)]
+    pub backoff_multiplier: u64,
+
+    /// Maximum wait in milliseconds of the consumer backoff.
+    ///
+    /// Default: **120000**
+    #[serde(skip)]
+    pub max_backoff_duration: u64,
+
+    /// Time in milliseconds given to the handlers to finish the messages in flight
+    /// when the client is drained, the connection is closed after it.
+    ///
+    /// Default: **30000**
+    #[serde(skip)]
+    pub drain_timeout: u64,
+
+    /// Bytes a connection may have left to write to nsqd before
+    /// [Context::send](struct.Context.html#method.send) blocks, 0 for no limit.
+    ///
+    /// Default: **1048576**
+    #[serde(skip)]
+    pub write_high_water_mark: u64,
+
+    /// Largest message body accepted from nsqd, match it with nsqd `--max-msg-size`.
+    /// Bigger frames are refused without being buffered and the connection is closed.
+    ///
+    /// Default: **1048576**
+    #[serde(skip)]
+    pub max_msg_size: u64,
+}
+
+/// TLS implementation, each one needs its cargo feature (`native-tls`, `rustls`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsBackend {
+    NativeTls,
+    Rustls,
+}
+
+impl Default for TlsBackend {
+    fn default() -> TlsBackend {
+        if cfg!(feature = "native-tls") {
+            TlsBackend::NativeTls
+        } else {
+            TlsBackend::Rustls
+        }
+    }
+}
+
+/// Verification of the certificate presented by nsqd.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum VerifyServerCert {
+    /// Accept any certificate.
+    None,
+    /// Verify the chain against the CA certificates of the given PEM file, and the
+    /// certificate against [tls_server_name](struct.Config.html#structfield.tls_server_name).
+    PrivateCA(String),
+    /// Verify against the public web PKI roots.
+    #[default]
+    PublicCA,
 }
 use hostname::get_hostname;
 
@@ -123,6 +288,26 @@ impl Default for Config {
             output_buffer_size: 16384,
             output_buffer_timeout: 250,
             sample_rate: 0,
+            max_reconnect_attempts: None,
+            lookupd_poll_interval: 60000,
+            tls_backend: TlsBackend::default(),
+            verify_server_cert: VerifyServerCert::default(),
+            tls_server_name: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+            tls_handshake_timeout: 10000,
+            batch_max_count: 100,
+            batch_max_size: 65536,
+            batch_linger: 10,
+            max_body_size: 5242880,
+            max_req_timeout: 3600000,
+            requeue_delay: 90000,
+            max_requeue_delay: 900000,
+            backoff_multiplier: 1000,
+            max_backoff_duration: 120000,
+            drain_timeout: 30000,
+            write_high_water_mark: 1048576,
+            max_msg_size: 1048576,
             //private_ca: String::new(),
         }
     }
@@ -204,6 +389,308 @@ impl Config {
         self
     }
 
+    /// Change [max_reconnect_attempts](struct.Config.html#structfield.max_reconnect_attempts)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().max_reconnect_attempts(10);
+    ///     assert_eq!(config.max_reconnect_attempts, Some(10));
+    /// }
+    /// ```
+    pub fn max_reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.max_reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// Change [lookupd_poll_interval](struct.Config.html#structfield.lookupd_poll_interval)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().lookupd_poll_interval(15000);
+    ///     assert_eq!(config.lookupd_poll_interval, 15000);
+    /// }
+    /// ```
+    pub fn lookupd_poll_interval(mut self, interval: u64) -> Self {
+        self.lookupd_poll_interval = interval;
+        self
+    }
+
+    /// Change [tls_backend](struct.Config.html#structfield.tls_backend)
+    /// ```no-run
+    /// use nsq_client::{Config, TlsBackend};
+    ///
+    /// fn main() {
+    ///     let config = Config::new().tls_backend(TlsBackend::Rustls);
+    ///     assert_eq!(config.tls_backend, TlsBackend::Rustls);
+    /// }
+    /// ```
+    pub fn tls_backend(mut self, backend: TlsBackend) -> Self {
+        self.tls_backend = backend;
+        self
+    }
+
+    /// Change [verify_server_cert](struct.Config.html#structfield.verify_server_cert)
+    /// ```no-run
+    /// use nsq_client::{Config, VerifyServerCert};
+    ///
+    /// fn main() {
+    ///     let config = Config::new()
+    ///         .verify_server_cert(VerifyServerCert::PrivateCA("ca.pem".to_owned()));
+    ///     assert_eq!(config.verify_server_cert, VerifyServerCert::PrivateCA("ca.pem".to_owned()));
+    /// }
+    /// ```
+    pub fn verify_server_cert(mut self, verify: VerifyServerCert) -> Self {
+        self.verify_server_cert = verify;
+        self
+    }
+
+    /// Change [tls_server_name](struct.Config.html#structfield.tls_server_name)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().tls_server_name("nsqd.internal");
+    ///     assert_eq!(config.tls_server_name, Some("nsqd.internal".to_owned()));
+    /// }
+    /// ```
+    pub fn tls_server_name(mut self, name: &str) -> Self {
+        self.tls_server_name = Some(name.to_owned());
+        self
+    }
+
+    /// Change [tls_client_cert](struct.Config.html#structfield.tls_client_cert)
+    /// and [tls_client_key](struct.Config.html#structfield.tls_client_key)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().tls_client_cert("client.pem", "client.key");
+    ///     assert_eq!(config.tls_client_cert, Some("client.pem".to_owned()));
+    ///     assert_eq!(config.tls_client_key, Some("client.key".to_owned()));
+    /// }
+    /// ```
+    pub fn tls_client_cert(mut self, cert: &str, key: &str) -> Self {
+        self.tls_client_cert = Some(cert.to_owned());
+        self.tls_client_key = Some(key.to_owned());
+        self
+    }
+
+    /// Change [tls_handshake_timeout](struct.Config.html#structfield.tls_handshake_timeout)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().tls_handshake_timeout(5000);
+    ///     assert_eq!(config.tls_handshake_timeout, 5000);
+    /// }
+    /// ```
+    pub fn tls_handshake_timeout(mut self, timeout: u64) -> Self {
+        self.tls_handshake_timeout = timeout;
+        self
+    }
+
+    /// Change the thresholds publishing a [Batcher](struct.Batcher.html) batch:
+    /// [batch_max_count](struct.Config.html#structfield.batch_max_count),
+    /// [batch_max_size](struct.Config.html#structfield.batch_max_size) and
+    /// [batch_linger](struct.Config.html#structfield.batch_linger)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().batch(500, 1048576, 50);
+    ///     assert_eq!(config.batch_max_count, 500);
+    ///     assert_eq!(config.batch_max_size, 1048576);
+    ///     assert_eq!(config.batch_linger, 50);
+    /// }
+    /// ```
+    pub fn batch(mut self, max_count: usize, max_size: usize, linger: u64) -> Self {
+        self.batch_max_count = max_count;
+        self.batch_max_size = max_size;
+        self.batch_linger = linger;
+        self
+    }
+
+    /// Change [max_body_size](struct.Config.html#structfield.max_body_size)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().max_body_size(1048576);
+    ///     assert_eq!(config.max_body_size, 1048576);
+    /// }
+    /// ```
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    /// Change [max_req_timeout](struct.Config.html#structfield.max_req_timeout)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().max_req_timeout(600000);
+    ///     assert_eq!(config.max_req_timeout, 600000);
+    /// }
+    /// ```
+    pub fn max_req_timeout(mut self, timeout: u64) -> Self {
+        self.max_req_timeout = timeout;
+        self
+    }
+
+    /// Change [requeue_delay](struct.Config.html#structfield.requeue_delay)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().requeue_delay(5000);
+    ///     assert_eq!(config.requeue_delay, 5000);
+    /// }
+    /// ```
+    pub fn requeue_delay(mut self, delay: u64) -> Self {
+        self.requeue_delay = delay;
+        self
+    }
+
+    /// Change [max_requeue_delay](struct.Config.html#structfield.max_requeue_delay)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().max_requeue_delay(60000);
+    ///     assert_eq!(config.max_requeue_delay, 60000);
+    /// }
+    /// ```
+    pub fn max_requeue_delay(mut self, delay: u64) -> Self {
+        self.max_requeue_delay = delay;
+        self
+    }
+
+    /// Change [backoff_multiplier](struct.Config.html#structfield.backoff_multiplier)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().backoff_multiplier(500);
+    ///     assert_eq!(config.backoff_multiplier, 500);
+    /// }
+    /// ```
+    pub fn backoff_multiplier(mut self, multiplier: u64) -> Self {
+        self.backoff_multiplier = multiplier;
+        self
+    }
+
+    /// Change [max_backoff_duration](struct.Config.html#structfield.max_backoff_duration)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().max_backoff_duration(60000);
+    ///     assert_eq!(config.max_backoff_duration, 60000);
+    /// }
+    /// ```
+    pub fn max_backoff_duration(mut self, duration: u64) -> Self {
+        self.max_backoff_duration = duration;
+        self
+    }
+
+    /// Change [drain_timeout](struct.Config.html#structfield.drain_timeout)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().drain_timeout(5000);
+    ///     assert_eq!(config.drain_timeout, 5000);
+    /// }
+    /// ```
+    pub fn drain_timeout(mut self, timeout: u64) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Change [write_high_water_mark](struct.Config.html#structfield.write_high_water_mark)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().write_high_water_mark(65536);
+    ///     assert_eq!(config.write_high_water_mark, 65536);
+    /// }
+    /// ```
+    pub fn write_high_water_mark(mut self, bytes: u64) -> Self {
+        self.write_high_water_mark = bytes;
+        self
+    }
+
+    /// Change [max_msg_size](struct.Config.html#structfield.max_msg_size)
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().max_msg_size(4194304);
+    ///     assert_eq!(config.max_msg_size, 4194304);
+    /// }
+    /// ```
+    pub fn max_msg_size(mut self, bytes: u64) -> Self {
+        self.max_msg_size = bytes;
+        self
+    }
+
+    /// Change [snappy](struct.Config.html#structfield.snappy)
+    ///
+    /// nsqd doesn't allow both compressions, enabling snappy disables deflate.
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().snappy(true);
+    ///     assert_eq!(config.snappy, true);
+    /// }
+    /// ```
+    pub fn snappy(mut self, snappy: bool) -> Self {
+        self.snappy = snappy;
+        if snappy {
+            self.deflate = false;
+        }
+        self
+    }
+
+    /// Change [deflate](struct.Config.html#structfield.deflate)
+    ///
+    /// nsqd doesn't allow both compressions, enabling deflate disables snappy.
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().deflate(true);
+    ///     assert_eq!(config.deflate, true);
+    /// }
+    /// ```
+    pub fn deflate(mut self, deflate: bool) -> Self {
+        self.deflate = deflate;
+        if deflate {
+            self.snappy = false;
+        }
+        self
+    }
+
+    /// Change [deflate_level](struct.Config.html#structfield.deflate_level),
+    /// the level is kept in the 1..=9 range.
+    /// ```no-run
+    /// use nsq_client::Config;
+    ///
+    /// fn main() {
+    ///     let config = Config::new().deflate(true).deflate_level(3);
+    ///     assert_eq!(config.deflate_level, 3);
+    /// }
+    /// ```
+    pub fn deflate_level(mut self, level: u16) -> Self {
+        self.deflate_level = level.clamp(1, 9);
+        self
+    }
+
     pub fn tls(&mut self) {
         self.tls_v1 = true;
     }
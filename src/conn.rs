@@ -1,53 +1,43 @@
-use crate::codec::{
-    write_cmd, write_magic, write_mmsg, write_msg, Response, FRAME_TYPE_ERROR, FRAME_TYPE_MESSAGE,
-    FRAME_TYPE_RESPONSE, HEADER_LENGTH, HEARTBEAT,
-};
+use crate::client::Route;
+use crate::codec::{encode_cmd, Frame, FrameDecoder, Response, HEARTBEAT};
+use crate::compression::Compression;
 use crate::config::Config;
-use crate::msgs::{
-    Auth, BytesMsg, Cmd, ConnInfo, ConnMsgInfo, Identify, NsqCmd, Rdy, Subscribe, VERSION,
-};
+use crate::error::ConnError;
+use crate::msgs::{BytesMsg, Cmd, ConnMsgInfo, NsqCmd, Rdy, FIN, REQ};
 //use crate::tls::TlsSession;
-use backoff::{backoff::Backoff, ExponentialBackoff};
-use byteorder::{BigEndian, ByteOrder};
 use bytes::BytesMut;
 use crossbeam::channel::{Receiver, Sender};
-use log::{debug, error, info};
-use mio::{net::TcpStream, Poll, PollOpt, Ready, Token};
+use log::{debug, error, info, trace};
+use mio::{net::TcpStream, Token};
 use std::fmt::Display;
 use std::io::{self, Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
-use std::process;
-use std::thread::{self, Thread};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::Instant;
 //use std::sync::{Arc, atomic::{Ordering, AtomicBool}};
 use chrono::{DateTime, Utc};
 
 pub const CONNECTION: Token = Token(5067);
 
-#[derive(Debug, PartialEq)]
-pub enum State {
-    Start,
-    Identify,
-    TlsNegotiating,
-    Tls,
-    Auth,
-    Subscribe,
-    Rdy,
-    Started,
-}
-
 #[derive(Debug)]
 pub struct Conn {
     //writing buffer where commands are written.
     w_buf: BytesMut,
-    //read buffer where data is decoded.
-    r_buf: BytesMut,
+    //bytes ready for the socket, kept until it takes them.
+    out: BytesMut,
+    //tls records the socket didn't take on the last flush.
+    unflushed: bool,
+    //frames read not decoded yet.
+    decoder: FrameDecoder,
     //send message to readers.
     //s: Sender<Msg>,
     s: Sender<BytesMsg>,
     // tcp_stream
     //receive Cmd from readers.
     r: Receiver<Cmd>,
+    #[allow(dead_code)]
     s_info: Sender<ConnMsgInfo>,
+    //route given to readers to send commands back on this connection.
+    route: Route,
     //heartbeat
     pub heartbeat: bool,
     //responses
@@ -56,13 +46,16 @@ pub struct Conn {
     config: Config,
     //msgs in flight
     in_flight: u32,
-    now: std::time::Instant,
+    //time of the last message received.
+    last_msg: Instant,
     processed: u32,
-    pub need_response: bool,
-    pub state: State,
+    // during the handshake decoding stops after each response, the stream may be
+    // upgraded right after it.
+    handshaking: bool,
     last_time_sent: i64,
-    handle: Thread,
     pub msg_timeout: u64,
+    //stream compression enabled after IDENTIFY.
+    compression: Option<Compression>,
 }
 
 impl Conn {
@@ -71,25 +64,28 @@ impl Conn {
         r: Receiver<Cmd>,
         s: Sender<BytesMsg>,
         s_info: Sender<ConnMsgInfo>,
+        route: Route,
         msg_timeout: u64,
     ) -> Conn {
         Conn {
-            r_buf: BytesMut::new(),
+            decoder: FrameDecoder::new().max_msg_size(config.max_msg_size as usize),
             w_buf: BytesMut::new(),
+            out: BytesMut::new(),
+            unflushed: false,
             r,
             s,
             heartbeat: false,
             config,
             responses: Vec::new(),
             in_flight: 0,
-            now: std::time::Instant::now(),
+            last_msg: Instant::now(),
             processed: 0,
-            need_response: false,
-            state: State::Start,
+            handshaking: true,
             s_info,
+            route,
             last_time_sent: 0,
-            handle: thread::current(),
-            msg_timeout: 0,
+            msg_timeout,
+            compression: None,
         }
     }
 
@@ -98,40 +94,27 @@ impl Conn {
     //        Ok(())
     //    }
 
-    pub fn magic(&mut self) {
-        write_magic(&mut self.w_buf, VERSION);
-        self.state = State::Identify;
-    }
-
-    pub fn identify(&mut self) {
-        let config = serde_json::to_string(&self.config).unwrap();
-        self.write_cmd(Identify(config).as_cmd());
-        self.state = State::Identify;
-        self.need_response = true;
-    }
-
-    pub fn auth(&mut self, secret: String) {
-        self.write_cmd(Auth(secret));
-        self.state = State::Auth;
-        self.need_response = true;
-    }
-
-    pub fn subscribe(&mut self, topic: String, channel: String) {
-        self.write_cmd(Subscribe(topic, channel));
-        self.state = State::Subscribe;
-        self.need_response = true;
+    /// Buffer of the commands to write on the next [write](#method.write).
+    pub fn w_buf(&mut self) -> &mut BytesMut {
+        &mut self.w_buf
     }
 
     pub fn rdy(&mut self, rdy: u32) {
         self.write_cmd(Rdy(rdy));
-        self.state = State::Started;
-        self.need_response = false;
     }
 
-    pub fn tls_enabled(&mut self, tls: &mut u8) {
-        *tls = 1;
-        debug!("tls enabled");
-        self.state = State::Tls;
+    /// Handshake done, every frame read is decoded from now on.
+    pub fn started(&mut self) {
+        self.handshaking = false;
+    }
+
+    /// Compress the stream, nsqd switches right after its last response
+    /// so the bytes already read past it are compressed too.
+    pub fn compress(&mut self, mut compression: Compression) -> io::Result<()> {
+        let leftover = self.decoder.take();
+        compression.decompress(&leftover, self.decoder.buffer_mut())?;
+        self.compression = Some(compression);
+        self.decode()
     }
 
     //    pub fn register(&mut self, poll: &mut Poll) {
@@ -144,9 +127,29 @@ impl Conn {
     //            .expect("cannot reregister socket on poll")
     //    }
 
-    pub fn get_response(&mut self, on_err: String) -> Result<String, ()> {
-        //self.poll_response();
-        get_response(self.responses.pop().unwrap(), on_err)
+    pub fn get_response(&mut self, on_err: String) -> Result<String, ConnError> {
+        match self.responses.pop() {
+            Some(resp) => get_response(resp, on_err),
+            None => Err(ConnError::Error(format!(
+                "{}: no response received",
+                on_err
+            ))),
+        }
+    }
+
+    /// Messages received and not yet finished or requeued.
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight
+    }
+
+    /// Bytes are waiting for the socket to be writable.
+    pub fn wants_write(&self) -> bool {
+        !self.out.is_empty() || self.unflushed
+    }
+
+    /// Time of the last message received.
+    pub fn last_msg(&self) -> Instant {
+        self.last_msg
     }
 
     pub fn heartbeat_done(&mut self) {
@@ -163,79 +166,66 @@ impl Conn {
 
     pub fn write_messages<STREAM: Read + Write>(&mut self, socket: &mut STREAM) {
         let msgs: Vec<Cmd> = self.r.try_iter().collect();
+        if msgs.is_empty() {
+            return;
+        }
         for msg in msgs {
-            let now: DateTime<Utc> = Utc::now();
+            let done = msg.cmd.starts_with(FIN) || msg.cmd.starts_with(REQ);
             self.write_cmd(msg);
-            if let Err(e) = self.write(socket) {
-                error!("error writing msg on socket: {:?}", e);
-            };
-            if let Err(e) = socket.flush() {
-                error!("error flushing socket: {:?}", e);
-            };
-            self.last_time_sent = now.timestamp();
-            self.in_flight -= 1;
-            self.processed += 1;
+            if done {
+                // commands sent after a reconnection may refer to messages of the old connection.
+                self.in_flight = self.in_flight.saturating_sub(1);
+                self.processed += 1;
+            }
         }
-        info!("inflight: {}", self.in_flight);
-        info!("processed {}", self.processed);
+        let now: DateTime<Utc> = Utc::now();
+        if let Err(e) = self.write(socket) {
+            error!("error writing msg on socket: {:?}", e);
+        };
+        self.last_time_sent = now.timestamp();
+        trace!("inflight: {}", self.in_flight);
+        trace!("processed {}", self.processed);
     }
 
-    pub fn decode(&mut self, size: usize) {
-        loop {
-            //buffer totally consumed
-            if self.r_buf.is_empty() {
-                return;
-            }
-            //readed socket bytes are not enought
-            if size < HEADER_LENGTH {
-                return;
-            }
-            let buf_len = self.r_buf.len();
-            if buf_len < HEADER_LENGTH {
-                return;
-            }
-            //read and check the frame size.
-            let frame_size = BigEndian::read_i32(&self.r_buf.as_ref()[..4]) as usize;
-            if size < frame_size {
-                return;
-            }
-            if buf_len < frame_size {
-                return;
-            }
-            //there is no more bytes to read for socket, split size and start decoding frame.
-            let _ = self.r_buf.split_to(4);
-            let frame_type = BigEndian::read_i32(&self.r_buf.split_to(4));
-            //take the whole frame for buffer.
-            let frame = self.r_buf.split_to(frame_size - 4);
-            if frame_type == FRAME_TYPE_MESSAGE {
-                let _ = self.s.send(BytesMsg(self.msg_timeout.clone(), frame));
-                self.in_flight += 1;
-                continue;
-            } else {
-                let s = std::str::from_utf8(frame.as_ref()).unwrap();
-                if s == HEARTBEAT {
-                    self.heartbeat = true;
-                    continue;
+    // hand the frames read to the handlers and the responses to the session.
+    fn decode(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.decoder.decode()? {
+            match frame {
+                Frame::Message(frame) => {
+                    let _ = self
+                        .s
+                        .send(BytesMsg(self.msg_timeout, frame, self.route.clone()));
+                    self.in_flight += 1;
+                    self.last_msg = Instant::now();
+                }
+                Frame::Response(resp) if resp == HEARTBEAT => self.heartbeat = true,
+                Frame::Response(resp) => {
+                    self.responses.push(Response::Response(resp));
+                    if self.handshaking {
+                        return Ok(());
+                    }
                 }
-                if frame_type == FRAME_TYPE_RESPONSE {
-                    self.responses.push(Response::Response(s.to_owned()));
-                } else if frame_type == FRAME_TYPE_ERROR {
-                    debug!("error received: {:?}", s.to_owned());
-                    self.responses.push(Response::Error(s.to_owned()));
+                Frame::Error(e) => {
+                    debug!("error received: {:?}", e);
+                    self.responses.push(Response::Error(e));
                 }
             }
         }
+        Ok(())
     }
 
     pub fn read_tcp<STREAM: Read + Write>(&mut self, socket: &mut STREAM) -> io::Result<usize> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.resize(self.config.output_buffer_size as usize, 0);
+        let mut buf: Vec<u8> = vec![0; self.config.output_buffer_size as usize];
         match socket.read(&mut buf) {
             Ok(0) => Ok(0),
             Ok(b) => {
-                self.r_buf.extend_from_slice(&buf.as_slice()[..b]);
-                self.decode(b);
-                //buf.clear();
+                match self.compression.as_mut() {
+                    Some(compression) => {
+                        compression.decompress(&buf[..b], self.decoder.buffer_mut())?;
+                    }
+                    None => self.decoder.extend(&buf[..b]),
+                };
+                self.decode()?;
                 Ok(b)
             }
             Err(e) => {
@@ -250,94 +240,244 @@ impl Conn {
     pub fn write_cmd<C: NsqCmd>(&mut self, msg: C) {
         let msg = msg.as_cmd();
         debug!("{:?}", msg);
-        write_cmd(&mut self.w_buf, &msg.cmd);
-        if msg.msg.is_empty() {
-            return;
-        }
-        if msg.msg.len() == 1 {
-            write_msg(&mut self.w_buf, msg.msg[0].clone());
-            return;
-        }
-        write_mmsg(&mut self.w_buf, msg.msg);
+        encode_cmd(&mut self.w_buf, msg);
     }
 
+    /// Write as much as the socket takes, the rest waits for
+    /// [wants_write](#method.wants_write) to be served on the next writable event.
     pub fn write_tcp<STREAM: Read + Write>(&mut self, socket: &mut STREAM) -> io::Result<usize> {
-        match socket.write(self.w_buf.as_ref()) {
-            Ok(0) => {
-                self.w_buf.clear();
-                Ok(0)
-            }
-            Ok(n) => {
-                self.w_buf.clear();
-                Ok(n)
+        if !self.w_buf.is_empty() {
+            let cmds = self.w_buf.take();
+            match self.compression.as_mut() {
+                Some(compression) => {
+                    let compressed = compression.compress(cmds.as_ref())?;
+                    self.out.extend_from_slice(&compressed);
+                }
+                None => self.out.extend_from_slice(&cmds),
             }
-            Err(e) => {
-                self.w_buf.clear();
-                Err(e)
+        }
+        let mut written = 0;
+        while !self.out.is_empty() {
+            match socket.write(self.out.as_ref()) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "nsqd socket closed for writing",
+                    ))
+                }
+                Ok(n) => {
+                    let _ = self.out.split_to(n);
+                    written += n;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
             }
         }
+        if self.out.is_empty() {
+            self.unflushed = match socket.flush() {
+                Ok(()) => false,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => true,
+                Err(e) => return Err(e),
+            };
+        }
+        self.route.set_backlog(self.out.len());
+        Ok(written)
     }
 }
 
 pub fn socket_connect(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let builder = if addr.is_ipv6() {
+        net2::TcpBuilder::new_v6()?
+    } else {
+        net2::TcpBuilder::new_v4()?
+    };
     let tcpstream = if cfg!(windows) {
-        let tcp_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 4150);
+        let ip = if addr.is_ipv6() {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+        let tcp_addr = SocketAddr::new(ip, 4150);
         debug!("{:?}", tcp_addr);
-        net2::TcpBuilder::new_v4()
-            .unwrap()
-            .bind(tcp_addr)
-            .expect("failed to create and bind tcp stream")
-            .to_tcp_stream()
-            .unwrap()
+        builder.bind(tcp_addr)?.to_tcp_stream()?
     } else {
-        net2::TcpBuilder::new_v4()
-            .expect("failed to create tcp stream")
-            .to_tcp_stream()
-            .unwrap()
+        builder.to_tcp_stream()?
     };
     info!("[{}] trying to connect to nsqd server", addr);
     TcpStream::connect_stream(tcpstream, &addr)
 }
 
-pub fn connect<A>(addr: A, output_buffer_size: u64) -> TcpStream
+pub fn connect<A>(addr: A, output_buffer_size: u64) -> Result<TcpStream, ConnError>
 where
     A: ToSocketAddrs + Display + Clone,
 {
-    //    let server_name: String = addr.clone().into();
-    let mut addrs = match addr.to_socket_addrs() {
+    // a failed lookup is reported as io error, dns may come back on the next attempt.
+    let addrs = match addr.to_socket_addrs() {
         Ok(addrs) => addrs,
         Err(e) => {
             error!("[{}] error on lookup: {}", addr, e);
-            process::exit(1);
+            return Err(ConnError::IoError(e));
         }
     };
-    let mut backoff = ExponentialBackoff::default();
-    loop {
-        let addr = addrs.next().expect("could not resove addr");
+    let mut last_err = io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        format!("[{}] could not resolve addr", addr),
+    );
+    for addr in addrs {
         match socket_connect(addr) {
             Ok(stream) => {
-                if let Err(e) = stream.set_recv_buffer_size(output_buffer_size as usize) {
-                    panic!("[{}] error on setting socket buffer size: {:?}", addr, e);
-                }
-                break stream;
+                stream.set_recv_buffer_size(output_buffer_size as usize)?;
+                return Ok(stream);
             }
             Err(e) => {
                 error!("[{}] error on connect to nsqd: {:?}", addr, e);
-                if let Some(timeout) = backoff.next_backoff() {
-                    thread::sleep(timeout);
-                }
+                last_err = e;
             }
         }
     }
+    Err(ConnError::IoError(last_err))
 }
 
-pub fn get_response(resp: Response, expect: String) -> Result<String, ()> {
+pub fn get_response(resp: Response, expect: String) -> Result<String, ConnError> {
     match resp {
         Response::Response(r) => Ok(r),
         Response::Error(e) => {
             error!("{}", expect);
             error!("error on response: {}", e);
-            Err(())
+            Err(ConnError::from_frame(&e))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Context;
+    use crate::msgs::Nop;
+    use crossbeam::channel;
+    use mio::Registration;
+    use std::thread;
+    use std::time::Duration;
+
+    // socket taking only `room` bytes before it would block.
+    struct Throttled {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Read for Throttled {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.room);
+            self.room -= n;
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn conn(high_water_mark: usize) -> (Conn, Route, Receiver<Cmd>) {
+        let (cmd_s, cmd_r) = channel::unbounded();
+        let (msg_s, _msg_r) = channel::unbounded();
+        let (info_s, _info_r) = channel::unbounded();
+        let (_reg, waker) = Registration::new2();
+        let route = Route::new(cmd_s, waker).high_water_mark(high_water_mark);
+        let conn = Conn::new(
+            Config::default(),
+            cmd_r.clone(),
+            msg_s,
+            info_s,
+            route.clone(),
+            0,
+        );
+        (conn, route, cmd_r)
+    }
+
+    #[test]
+    fn resolve_failure() {
+        // .invalid never resolves, a missing port doesn't even reach dns.
+        for addr in ["nsqd.invalid:4150", "nsqd"].iter() {
+            match connect(*addr, 0) {
+                Err(ConnError::IoError(_)) => {}
+                res => panic!("expected an io error, got {:?}", res.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn partial_writes() {
+        let (mut conn, _route, _cmd_r) = conn(0);
+        let mut socket = Throttled {
+            written: Vec::new(),
+            room: 4,
+        };
+        conn.rdy(10);
+        assert_eq!(conn.write(&mut socket).unwrap(), 4);
+        assert!(conn.wants_write());
+        // commands written meanwhile go after the bytes left behind.
+        conn.write_cmd(Nop);
+        assert_eq!(conn.write(&mut socket).unwrap(), 0);
+        socket.room = 64;
+        assert_eq!(conn.write(&mut socket).unwrap(), 7);
+        assert!(!conn.wants_write());
+        assert_eq!(&socket.written[..], &b"RDY 10\nNOP\n"[..]);
+    }
+
+    #[test]
+    fn write_zero() {
+        struct Closed;
+
+        impl Read for Closed {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+        }
+
+        impl Write for Closed {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (mut conn, _route, _cmd_r) = conn(0);
+        conn.write_cmd(Nop);
+        let err = conn.write(&mut Closed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn send_waits_for_backlog() {
+        let (mut conn, route, cmd_r) = conn(4);
+        let mut socket = Throttled {
+            written: Vec::new(),
+            room: 2,
+        };
+        conn.rdy(10);
+        conn.write(&mut socket).unwrap();
+        let sender = thread::spawn(move || {
+            let mut ctx = Context::new(route, &Config::default());
+            ctx.send(Nop);
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(cmd_r.is_empty());
+        socket.room = 64;
+        conn.write(&mut socket).unwrap();
+        sender.join().unwrap();
+        assert_eq!(cmd_r.recv().unwrap().cmd, "NOP");
+    }
+}
@@ -0,0 +1,216 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Context;
+use crate::error::ConnError;
+use crate::msgs::{Fin, Msg};
+use crate::publisher::Publisher;
+use crate::reader::Disposition;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+/// Topic where a [Handler](trait.Handler.html) sends the messages it keeps failing on,
+/// see [Client::dead_letter](struct.Client.html#method.dead_letter).
+///
+/// The message published is the json [DeadLetterInfo](struct.DeadLetterInfo.html),
+/// a newline and the original body.
+/// ```no-run
+/// use nsq_client::{Config, DeadLetter, Publisher};
+///
+/// let publisher = Publisher::connect("127.0.0.1:4150", Config::new(), None).unwrap();
+/// let dead_letter = DeadLetter::new("orders.dead", publisher);
+/// ```
+#[derive(Clone)]
+pub struct DeadLetter {
+    topic: String,
+    publisher: Publisher,
+    // where the messages come from, given by the client.
+    source_topic: String,
+    channel: String,
+}
+
+impl DeadLetter {
+    pub fn new<T: Into<String>>(topic: T, publisher: Publisher) -> DeadLetter {
+        DeadLetter {
+            topic: topic.into(),
+            publisher,
+            source_topic: String::new(),
+            channel: String::new(),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub(crate) fn source(mut self, topic: &str, channel: &str) -> DeadLetter {
+        self.source_topic = topic.to_owned();
+        self.channel = channel.to_owned();
+        self
+    }
+
+    // publish the message then FIN it, it is requeued if the publish fails.
+    pub(crate) fn send(&self, msg: Msg, error: &str, ctx: &mut Context) {
+        let info = DeadLetterInfo {
+            topic: self.source_topic.clone(),
+            channel: self.channel.clone(),
+            id: msg.id.to_string(),
+            attempts: msg.attemps,
+            timestamp: msg.timestamp,
+            error: error.to_owned(),
+        };
+        let body = match info.encode(&msg.body) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("message {} not dead-lettered: {}", msg.id, e);
+                ctx.dispose(msg.id, msg.attemps, Disposition::RequeueWithBackoff);
+                return;
+            }
+        };
+        match self.publisher.publish(self.topic.clone(), body) {
+            Ok(()) => {
+                warn!(
+                    "message {} sent to {} after {} attempts: {}",
+                    msg.id, self.topic, msg.attemps, error
+                );
+                // the message is gone, the handler doesn't back off for it.
+                ctx.send(Fin(msg.id));
+            }
+            Err(e) => {
+                error!("message {} not dead-lettered, requeued: {}", msg.id, e);
+                ctx.dispose(msg.id, msg.attemps, Disposition::RequeueWithBackoff);
+            }
+        }
+    }
+}
+
+/// Metadata of a message published to a [DeadLetter](struct.DeadLetter.html) topic.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetterInfo {
+    /// Topic the message was consumed from.
+    pub topic: String,
+    pub channel: String,
+    pub id: String,
+    pub attempts: u16,
+    /// Timestamp of the original message, in nanoseconds.
+    pub timestamp: i64,
+    /// Last error of the handler.
+    pub error: String,
+}
+
+impl DeadLetterInfo {
+    /// Split a message of a dead-letter topic into its metadata and the original body.
+    pub fn parse(body: &[u8]) -> Result<(DeadLetterInfo, &[u8]), ConnError> {
+        let end = body
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| ConnError::Error("not a dead-lettered message".to_owned()))?;
+        let info = serde_json::from_slice(&body[..end])?;
+        Ok((info, &body[end + 1..]))
+    }
+
+    fn encode(&self, body: &[u8]) -> Result<Vec<u8>, ConnError> {
+        let mut buf = serde_json::to_vec(self)?;
+        buf.push(b'\n');
+        buf.extend_from_slice(body);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Route;
+    use crate::config::Config;
+    use crate::error::HandlerError;
+    use crate::publisher::tests::nsqd;
+    use crate::reader::tests::msg;
+    use crate::reader::{deliver, Disposer, Handler};
+    use crossbeam::channel;
+    use mio::Registration;
+
+    #[derive(Clone)]
+    struct Failing;
+
+    impl Handler for Failing {
+        type Outcome = Result<(), HandlerError>;
+
+        fn handle(&mut self, _msg: Msg, _ctx: &mut Context) -> Self::Outcome {
+            Err(HandlerError::new("failed"))
+        }
+    }
+
+    fn refused(cmd: &str) -> Option<&'static str> {
+        if cmd == "PUB refused.dead" {
+            return Some("E_BAD_TOPIC refused.dead");
+        }
+        None
+    }
+
+    #[test]
+    fn encode_and_parse() {
+        let info = DeadLetterInfo {
+            topic: "t".to_owned(),
+            channel: "c".to_owned(),
+            id: "1".to_owned(),
+            attempts: 5,
+            timestamp: 0,
+            error: "line\nbreak".to_owned(),
+        };
+        let body = info.encode(b"\x00body\n").unwrap();
+        let (parsed, original) = DeadLetterInfo::parse(&body).unwrap();
+        assert_eq!(parsed, info);
+        assert_eq!(original, b"\x00body\n");
+        assert!(DeadLetterInfo::parse(b"body").is_err());
+    }
+
+    #[test]
+    fn dead_letter_after_last_attempt() {
+        let (addr, handle) = nsqd(1, refused);
+        let publisher = Publisher::connect(addr, Config::default(), None).unwrap();
+        let dead = DeadLetter::new("t.dead", publisher.clone()).source("t", "c");
+        let refused = DeadLetter::new("refused.dead", publisher.clone()).source("t", "c");
+        let (cmd_s, cmd_r) = channel::unbounded();
+        let (_registration, waker) = Registration::new2();
+        let mut ctx = Context::new(Route::new(cmd_s, waker), &Config::new());
+        let mut consumer = Disposer::new(Failing).dead_letter(dead, 2);
+        deliver(&mut consumer, msg("1", 1, b"poison"), 2, &mut ctx);
+        deliver(&mut consumer, msg("2", 2, b"poison"), 2, &mut ctx);
+        deliver(&mut consumer, msg("3", 3, b"poison"), 2, &mut ctx);
+        let mut consumer = Disposer::new(Failing).dead_letter(refused, 2);
+        deliver(&mut consumer, msg("4", 2, b"poison"), 2, &mut ctx);
+        drop(publisher);
+        let cmds: Vec<String> = cmd_r.try_iter().map(|cmd| cmd.cmd).collect();
+        assert_eq!(
+            cmds,
+            [
+                "REQ 0000000000000001 90000",
+                "FIN 0000000000000002",
+                "FIN 0000000000000003",
+                "REQ 0000000000000004 180000"
+            ]
+        );
+        let pubs = handle.join().unwrap();
+        assert_eq!(pubs, ["PUB t.dead", "PUB t.dead", "PUB refused.dead"]);
+    }
+}
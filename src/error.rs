@@ -21,35 +21,130 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use std::io;
+use serde_json::error::Error as JsnError;
 use std::error::Error;
 use std::fmt;
-use serde_json::error::Error as JsnError;
+use std::io;
+use std::time::Duration;
 
+/// Errors returned by the client.
+///
+/// Protocol variants map the error frames sent by nsqd, they carry the
+/// description which follows the error code.
 #[derive(Debug)]
 pub enum ConnError {
     Error(String),
     IoError(io::Error),
-    TlsError(NativeTlsError),
+    /// TLS failure, from the backend in use.
+    TlsError(Box<dyn Error + Send + Sync>),
     JsonError(JsnError),
+    /// nsqd requires authentication and no secret was given.
+    AuthRequired,
+    /// E_INVALID
+    Invalid(String),
+    /// E_BAD_BODY
+    BadBody(String),
+    /// E_BAD_TOPIC
+    BadTopic(String),
+    /// E_BAD_CHANNEL
+    BadChannel(String),
+    /// E_BAD_MESSAGE
+    BadMessage(String),
+    /// E_PUB_FAILED
+    PubFailed(String),
+    /// E_MPUB_FAILED
+    MpubFailed(String),
+    /// E_DPUB_FAILED
+    DpubFailed(String),
+    /// E_FIN_FAILED
+    FinFailed(String),
+    /// E_REQ_FAILED
+    ReqFailed(String),
+    /// E_TOUCH_FAILED
+    TouchFailed(String),
+    /// E_AUTH_FAILED
+    AuthFailed(String),
+    /// E_AUTH_DISABLED
+    AuthDisabled(String),
+    /// E_UNAUTHORIZED
+    Unauthorized(String),
+    /// Error frame with an unknown code.
+    Protocol(String),
+    /// DPUB or REQ delay refused before being sent.
+    Delay(DelayError),
 }
 
-impl Error for ConnError {
-    fn description(&self) -> &str {
-        match self {
-            ConnError::Error(s) => s,
-            ConnError::IoError(e) => e.description(),
-            ConnError::TlsError(e) => e.description(),
-            ConnError::JsonError(e) => e.description(),
+impl ConnError {
+    /// Build the error from the content of an nsqd error frame (ex. `E_BAD_TOPIC invalid topic`).
+    pub fn from_frame(frame: &str) -> ConnError {
+        let mut parts = frame.splitn(2, ' ');
+        let code = parts.next().unwrap_or_default();
+        let desc = parts.next().unwrap_or_default().to_owned();
+        match code {
+            "E_INVALID" => ConnError::Invalid(desc),
+            "E_BAD_BODY" => ConnError::BadBody(desc),
+            "E_BAD_TOPIC" => ConnError::BadTopic(desc),
+            "E_BAD_CHANNEL" => ConnError::BadChannel(desc),
+            "E_BAD_MESSAGE" => ConnError::BadMessage(desc),
+            "E_PUB_FAILED" => ConnError::PubFailed(desc),
+            "E_MPUB_FAILED" => ConnError::MpubFailed(desc),
+            "E_DPUB_FAILED" => ConnError::DpubFailed(desc),
+            "E_FIN_FAILED" => ConnError::FinFailed(desc),
+            "E_REQ_FAILED" => ConnError::ReqFailed(desc),
+            "E_TOUCH_FAILED" => ConnError::TouchFailed(desc),
+            "E_AUTH_FAILED" => ConnError::AuthFailed(desc),
+            "E_AUTH_DISABLED" => ConnError::AuthDisabled(desc),
+            "E_UNAUTHORIZED" => ConnError::Unauthorized(desc),
+            _ => ConnError::Protocol(frame.to_owned()),
         }
     }
 
-    fn cause(&self) -> Option<&Error> {
+    // copy of an error built by from_frame, the other variants can't be cloned.
+    pub(crate) fn clone_frame(&self) -> Option<ConnError> {
+        let e = match self {
+            ConnError::Invalid(desc) => ConnError::Invalid(desc.clone()),
+            ConnError::BadBody(desc) => ConnError::BadBody(desc.clone()),
+            ConnError::BadTopic(desc) => ConnError::BadTopic(desc.clone()),
+            ConnError::BadChannel(desc) => ConnError::BadChannel(desc.clone()),
+            ConnError::BadMessage(desc) => ConnError::BadMessage(desc.clone()),
+            ConnError::PubFailed(desc) => ConnError::PubFailed(desc.clone()),
+            ConnError::MpubFailed(desc) => ConnError::MpubFailed(desc.clone()),
+            ConnError::DpubFailed(desc) => ConnError::DpubFailed(desc.clone()),
+            ConnError::FinFailed(desc) => ConnError::FinFailed(desc.clone()),
+            ConnError::ReqFailed(desc) => ConnError::ReqFailed(desc.clone()),
+            ConnError::TouchFailed(desc) => ConnError::TouchFailed(desc.clone()),
+            ConnError::AuthFailed(desc) => ConnError::AuthFailed(desc.clone()),
+            ConnError::AuthDisabled(desc) => ConnError::AuthDisabled(desc.clone()),
+            ConnError::Unauthorized(desc) => ConnError::Unauthorized(desc.clone()),
+            ConnError::Protocol(frame) => ConnError::Protocol(frame.clone()),
+            _ => return None,
+        };
+        Some(e)
+    }
+
+    /// nsqd closes the connection after every error except E_FIN_FAILED,
+    /// E_REQ_FAILED and E_TOUCH_FAILED.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            ConnError::FinFailed(_) | ConnError::ReqFailed(_) | ConnError::TouchFailed(_)
+        )
+    }
+
+    /// Errors worth a reconnection, the others won't go away by connecting again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ConnError::IoError(_) | ConnError::TlsError(_))
+    }
+}
+
+impl Error for ConnError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            ConnError::Error(_) => None,
             ConnError::IoError(e) => Some(e),
-            ConnError::TlsError(e) => Some(e),
+            ConnError::TlsError(e) => Some(e.as_ref()),
             ConnError::JsonError(e) => Some(e),
+            ConnError::Delay(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -58,9 +153,178 @@ impl fmt::Display for ConnError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ConnError::Error(s) => write!(f, "{}", s),
-            ConnError::IoError(e) => write!(f, "{}", e.description()),
-            ConnError::TlsError(e) => write!(f, "{}", e.description()),
-            ConnError::JsonError(e) => write!(f, "{}", e.description()),
+            ConnError::IoError(e) => write!(f, "{}", e),
+            ConnError::TlsError(e) => write!(f, "{}", e),
+            ConnError::JsonError(e) => write!(f, "{}", e),
+            ConnError::AuthRequired => write!(f, "authentication required, secret token needed"),
+            ConnError::Invalid(s) => write!(f, "E_INVALID {}", s),
+            ConnError::BadBody(s) => write!(f, "E_BAD_BODY {}", s),
+            ConnError::BadTopic(s) => write!(f, "E_BAD_TOPIC {}", s),
+            ConnError::BadChannel(s) => write!(f, "E_BAD_CHANNEL {}", s),
+            ConnError::BadMessage(s) => write!(f, "E_BAD_MESSAGE {}", s),
+            ConnError::PubFailed(s) => write!(f, "E_PUB_FAILED {}", s),
+            ConnError::MpubFailed(s) => write!(f, "E_MPUB_FAILED {}", s),
+            ConnError::DpubFailed(s) => write!(f, "E_DPUB_FAILED {}", s),
+            ConnError::FinFailed(s) => write!(f, "E_FIN_FAILED {}", s),
+            ConnError::ReqFailed(s) => write!(f, "E_REQ_FAILED {}", s),
+            ConnError::TouchFailed(s) => write!(f, "E_TOUCH_FAILED {}", s),
+            ConnError::AuthFailed(s) => write!(f, "E_AUTH_FAILED {}", s),
+            ConnError::AuthDisabled(s) => write!(f, "E_AUTH_DISABLED {}", s),
+            ConnError::Unauthorized(s) => write!(f, "E_UNAUTHORIZED {}", s),
+            ConnError::Protocol(s) => write!(f, "{}", s),
+            ConnError::Delay(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Failure of a publish made through a [Publisher](struct.Publisher.html).
+#[derive(Debug)]
+pub enum PubError {
+    /// nsqd answered with an error frame, the message was not published.
+    Nsqd(ConnError),
+    /// The connection was lost before nsqd answered, the message may have been published.
+    ConnectionLost,
+    /// The publisher is closed.
+    Closed,
+    /// The message body is larger than
+    /// [max_body_size](struct.Config.html#structfield.max_body_size).
+    BodyTooLarge(usize),
+    /// The DPUB delay is longer than
+    /// [max_req_timeout](struct.Config.html#structfield.max_req_timeout).
+    Delay(DelayError),
+}
+
+impl PubError {
+    /// Errors worth publishing again on another nsqd, the others would be refused the same way.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PubError::Nsqd(e) => matches!(
+                e,
+                ConnError::PubFailed(_) | ConnError::MpubFailed(_) | ConnError::DpubFailed(_)
+            ),
+            PubError::ConnectionLost | PubError::Closed => true,
+            PubError::BodyTooLarge(_) | PubError::Delay(_) => false,
+        }
+    }
+}
+
+impl Error for PubError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PubError::Nsqd(e) => Some(e),
+            PubError::Delay(e) => Some(e),
+            _ => None,
         }
     }
 }
+
+impl fmt::Display for PubError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PubError::Nsqd(e) => write!(f, "{}", e),
+            PubError::ConnectionLost => write!(f, "connection lost before nsqd answered"),
+            PubError::Closed => write!(f, "publisher closed"),
+            PubError::BodyTooLarge(size) => {
+                write!(f, "message body of {} bytes exceeds max_body_size", size)
+            }
+            PubError::Delay(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Failure of a [Handler](trait.Handler.html), the message is requeued.
+///
+/// Built from any error, `?` works in handlers.
+#[derive(Debug)]
+pub struct HandlerError {
+    reason: String,
+    delay: Option<Duration>,
+}
+
+impl HandlerError {
+    pub fn new<R: fmt::Display>(reason: R) -> HandlerError {
+        HandlerError {
+            reason: reason.to_string(),
+            delay: None,
+        }
+    }
+
+    /// Requeue after delay instead of the backoff given by the attempts of the message.
+    pub fn requeue_after(mut self, delay: Duration) -> HandlerError {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay
+    }
+}
+
+impl<E: Error> From<E> for HandlerError {
+    fn from(e: E) -> HandlerError {
+        HandlerError::new(e)
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Delay of a DPUB or REQ longer than nsqd accepts
+/// ([max_req_timeout](struct.Config.html#structfield.max_req_timeout)).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DelayError {
+    pub delay: Duration,
+    pub max: Duration,
+}
+
+impl Error for DelayError {}
+
+impl fmt::Display for DelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "delay of {}ms exceeds max_req_timeout of {}ms",
+            self.delay.as_millis(),
+            self.max.as_millis()
+        )
+    }
+}
+
+impl From<DelayError> for ConnError {
+    fn from(e: DelayError) -> ConnError {
+        ConnError::Delay(e)
+    }
+}
+
+impl From<io::Error> for ConnError {
+    fn from(e: io::Error) -> ConnError {
+        ConnError::IoError(e)
+    }
+}
+
+#[cfg(feature = "native-tls")]
+impl From<native_tls::Error> for ConnError {
+    fn from(e: native_tls::Error) -> ConnError {
+        ConnError::TlsError(Box::new(e))
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<rustls::Error> for ConnError {
+    fn from(e: rustls::Error) -> ConnError {
+        ConnError::TlsError(Box::new(e))
+    }
+}
+
+impl From<JsnError> for ConnError {
+    fn from(e: JsnError) -> ConnError {
+        ConnError::JsonError(e)
+    }
+}
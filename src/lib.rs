@@ -1,24 +1,50 @@
-#![feature(async_await)]
 extern crate bytes;
 extern crate log;
 extern crate mio;
 //extern crate webpki;
 //extern crate webpki_roots;
 
-//#[cfg(feature = "async")]
-//mod async_context;
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_context;
+mod auth;
+mod batch;
 mod client;
 mod codec;
+mod compression;
 mod config;
 mod conn;
+mod dead_letter;
+mod error;
+mod lookup;
 mod msgs;
+mod pool;
 mod producer;
+mod publisher;
+mod rdy;
 mod reader;
-//mod tls;
+mod state;
+mod tls;
 
-pub use client::{Client, Context};
-pub use config::Config;
+#[cfg(feature = "async")]
+pub use async_client::{AsyncClient, MsgStream};
+#[cfg(feature = "async")]
+pub use async_context::ContextAsync;
+pub use auth::AuthResp;
+pub use batch::Batcher;
+pub use client::{Client, ClientHandle, Context};
+pub use config::{Config, TlsBackend, VerifyServerCert};
 pub use conn::Conn;
-pub use msgs::{Cls, Cmd, ConnMsg, ConnMsgInfo, Dpub, Fin, Mpub, Msg, NsqCmd, Pub, Requeue, Touch};
+pub use dead_letter::{DeadLetter, DeadLetterInfo};
+pub use error::{ConnError, DelayError, HandlerError, PubError};
+pub use msgs::{
+    AuthInfo, BackoffInfo, Cls, Cmd, ConnInfo, ConnMsg, ConnMsgInfo, Dpub, Fin, MessageId, Mpub,
+    Msg, NsqCmd, Pub, ReconnectInfo, Requeue, Touch,
+};
+pub use pool::{PublisherPool, Selection};
 pub use producer::Producer;
-pub use reader::Consumer;
+pub use publisher::Publisher;
+#[cfg(feature = "async")]
+pub use reader::AsyncConsumer;
+pub use reader::{Consumer, Disposition, Handler, IntoDisposition};
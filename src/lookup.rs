@@ -0,0 +1,247 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Timeout applied to connect, read and write on nsqlookupd.
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ACCEPT: &str = "application/vnd.nsq; version=1.0";
+
+/// nsqd node producing a topic, as reported by nsqlookupd.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct LookupProducer {
+    pub broadcast_address: String,
+    #[serde(default)]
+    pub hostname: String,
+    pub tcp_port: u16,
+    #[serde(default)]
+    pub http_port: u16,
+    #[serde(default)]
+    pub version: String,
+}
+
+impl LookupProducer {
+    /// tcp address used to connect to nsqd, IPv6 addresses are bracketed.
+    pub fn addr(&self) -> String {
+        let host = &self.broadcast_address;
+        if host.contains(':') && !host.starts_with('[') {
+            format!("[{}]:{}", host, self.tcp_port)
+        } else {
+            format!("{}:{}", host, self.tcp_port)
+        }
+    }
+}
+
+/// Response of nsqlookupd `/lookup` endpoint.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct LookupResp {
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub producers: Vec<LookupProducer>,
+}
+
+/// Query nsqlookupd for the nsqd nodes producing `topic`.
+///
+/// `lookupd` is the nsqlookupd http address, with or without the `http://` scheme.
+/// An unknown topic is not an error, it returns no producers.
+pub fn lookup(lookupd: &str, topic: &str, timeout: Duration) -> io::Result<LookupResp> {
+    let host = lookupd.trim_start_matches("http://").trim_end_matches('/');
+    let addr = host.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("[{}] could not resolve addr", host),
+        )
+    })?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET /lookup?topic={} HTTP/1.0\r\nHost: {}\r\nAccept: {}\r\n\r\n",
+        encode(topic),
+        host,
+        ACCEPT
+    )?;
+    // HTTP/1.0: nsqlookupd closes the connection after the response.
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp)?;
+    let (status, body) = split_response(&resp)?;
+    match status {
+        200 => decode_lookup(body),
+        404 => Ok(LookupResp::default()),
+        _ => Err(io::Error::other(format!(
+            "[{}] lookup failed with status {}: {}",
+            host,
+            status,
+            String::from_utf8_lossy(body)
+        ))),
+    }
+}
+
+fn decode_lookup(body: &[u8]) -> io::Result<LookupResp> {
+    let resp: Value = serde_json::from_slice(body)?;
+    // nsqlookupd < 1.0 wraps the response in a data envelope.
+    let resp = match resp.get("data") {
+        Some(data) => data.clone(),
+        None => resp,
+    };
+    Ok(serde_json::from_value(resp)?)
+}
+
+// split an http response in status code and body.
+fn split_response(resp: &[u8]) -> io::Result<(u16, &[u8])> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid http response");
+    let end = resp
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&resp[..end]).map_err(|_| invalid())?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, &resp[end + 4..]))
+}
+
+// percent-encode a query string value.
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // serve a single canned response and return the request line received.
+    fn lookupd(status: &'static str, body: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            write!(
+                stream,
+                "HTTP/1.0 {}\r\nContent-Type: application/json\r\n\r\n{}",
+                status, body
+            )
+            .unwrap();
+            request
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn lookup_producers() {
+        let (addr, handle) = lookupd(
+            "200 OK",
+            r#"{"channels":["c"],"producers":[
+                {"remote_address":"127.0.0.1:51234","hostname":"nsqd-1","broadcast_address":"10.0.0.1","tcp_port":4150,"http_port":4151,"version":"1.2.0"},
+                {"remote_address":"127.0.0.1:51235","hostname":"nsqd-2","broadcast_address":"10.0.0.2","tcp_port":4250,"http_port":4251,"version":"1.2.0"}
+            ]}"#,
+        );
+        let resp = lookup(
+            &format!("http://{}/", addr),
+            "test#ephemeral",
+            LOOKUP_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(
+            handle.join().unwrap(),
+            "GET /lookup?topic=test%23ephemeral HTTP/1.0\r\n"
+        );
+        assert_eq!(resp.channels, vec!["c".to_owned()]);
+        let addrs: Vec<String> = resp.producers.iter().map(|p| p.addr()).collect();
+        assert_eq!(addrs, vec!["10.0.0.1:4150", "10.0.0.2:4250"]);
+    }
+
+    #[test]
+    fn lookup_legacy_envelope() {
+        let (addr, _) = lookupd(
+            "200 OK",
+            r#"{"status_code":200,"status_txt":"OK","data":{"channels":[],"producers":[
+                {"broadcast_address":"nsqd","tcp_port":4150}
+            ]}}"#,
+        );
+        let resp = lookup(&addr, "test", LOOKUP_TIMEOUT).unwrap();
+        assert_eq!(resp.producers.len(), 1);
+        assert_eq!(resp.producers[0].addr(), "nsqd:4150");
+    }
+
+    #[test]
+    fn ipv6_broadcast_address() {
+        let producer = |broadcast_address: &str| LookupProducer {
+            broadcast_address: broadcast_address.to_owned(),
+            hostname: String::new(),
+            tcp_port: 4150,
+            http_port: 4151,
+            version: String::new(),
+        };
+        assert_eq!(producer("::1").addr(), "[::1]:4150");
+        assert_eq!(producer("[fd00::1]").addr(), "[fd00::1]:4150");
+        assert_eq!(producer("10.0.0.1").addr(), "10.0.0.1:4150");
+        assert_eq!(producer("nsqd").addr(), "nsqd:4150");
+    }
+
+    #[test]
+    fn lookup_unknown_topic() {
+        let (addr, _) = lookupd("404 Not Found", r#"{"message":"TOPIC_NOT_FOUND"}"#);
+        let resp = lookup(&addr, "test", LOOKUP_TIMEOUT).unwrap();
+        assert_eq!(resp, LookupResp::default());
+    }
+
+    #[test]
+    fn lookup_server_error() {
+        let (addr, _) = lookupd(
+            "500 Internal Server Error",
+            r#"{"message":"INTERNAL_ERROR"}"#,
+        );
+        assert!(lookup(&addr, "test", LOOKUP_TIMEOUT).is_err());
+    }
+}
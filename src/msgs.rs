@@ -21,21 +21,27 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use bytes::BytesMut;
+use crate::auth::AuthResp;
+use crate::client::Route;
+use crate::error::{ConnError, DelayError};
+use bytes::{Bytes, BytesMut};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 pub const VERSION: &str = "  V2";
 const PUB: &str = "PUB";
-const MPUB: &str = "MPUB";
+pub const MPUB: &str = "MPUB";
 const DPUB: &str = "DPUB";
 const SUB: &str = "SUB";
 const TOUCH: &str = "TOUCH";
 const RDY: &str = "RDY";
-const FIN: &str = "FIN";
+pub const FIN: &str = "FIN";
 const CLS: &str = "CLS";
 const AUTH: &str = "AUTH";
 pub const NOP: &str = "NOP";
 const IDENTIFY: &str = "IDENTIFY";
-const REQ: &str = "REQ";
+pub const REQ: &str = "REQ";
 
 pub trait NsqCmd: Send {
     fn cmd(&self) -> String;
@@ -45,6 +51,18 @@ pub trait NsqCmd: Send {
     fn as_cmd(&self) -> Cmd {
         Cmd::new(self.cmd(), self.msg())
     }
+    /// Delay asked to nsqd (DPUB and REQ).
+    fn delay(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// delays longer than nsqd --max-req-timeout are refused by nsqd.
+pub(crate) fn check_delay<C: NsqCmd>(cmd: &C, max: Duration) -> Result<(), DelayError> {
+    match cmd.delay() {
+        Some(delay) if delay > max => Err(DelayError { delay, max }),
+        _ => Ok(()),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -69,14 +87,12 @@ impl NsqCmd for Cmd {
     }
 }
 
-pub trait Message: Send + 'static {}
-
-pub struct Fin(pub String);
-pub struct Touch(pub String);
-pub struct Requeue(pub String, pub u32);
+pub struct Fin(pub MessageId);
+pub struct Touch(pub MessageId);
+pub struct Requeue(pub MessageId, pub Duration);
 pub struct Pub(pub String, pub Vec<u8>);
 pub struct Mpub(pub String, pub Vec<Vec<u8>>);
-pub struct Dpub(pub String, pub u32, pub Vec<u8>);
+pub struct Dpub(pub String, pub Duration, pub Vec<u8>);
 pub struct Identify(pub String);
 pub struct Subscribe(pub String, pub String);
 pub struct Auth(pub String);
@@ -84,17 +100,59 @@ pub struct Nop;
 pub struct Cls;
 pub struct Rdy(pub u32);
 
+/// Id given by nsqd to a message, 16 ascii bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageId(pub [u8; 16]);
+
+impl MessageId {
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Debug for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MessageId({})", self)
+    }
+}
+
+impl From<[u8; 16]> for MessageId {
+    fn from(id: [u8; 16]) -> MessageId {
+        MessageId(id)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = ConnError;
+
+    fn from_str(s: &str) -> Result<MessageId, ConnError> {
+        if s.len() != 16 {
+            return Err(ConnError::Error(format!("invalid message id {}", s)));
+        }
+        let mut id = [0; 16];
+        id.copy_from_slice(s.as_bytes());
+        Ok(MessageId(id))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Msg {
     pub timeout: u64,
     pub timestamp: i64,
     pub attemps: u16,
-    pub id: String,
-    pub body: Vec<u8>,
+    pub id: MessageId,
+    /// Slice of the frame read from nsqd.
+    pub body: Bytes,
 }
 
 #[derive(Debug, Clone)]
-pub struct BytesMsg(pub u64, pub BytesMut);
+pub struct BytesMsg(pub u64, pub BytesMut, pub Route);
 
 impl NsqCmd for Auth {
     fn cmd(&self) -> String {
@@ -154,7 +212,11 @@ impl NsqCmd for Touch {
 
 impl NsqCmd for Requeue {
     fn cmd(&self) -> String {
-        format!("{} {} {}", REQ, self.0, self.1)
+        format!("{} {} {}", REQ, self.0, self.1.as_millis())
+    }
+
+    fn delay(&self) -> Option<Duration> {
+        Some(self.1)
     }
 }
 
@@ -180,17 +242,24 @@ impl NsqCmd for Mpub {
 
 impl NsqCmd for Dpub {
     fn cmd(&self) -> String {
-        format!("{} {} {}", DPUB, self.0, self.1)
+        format!("{} {} {}", DPUB, self.0, self.1.as_millis())
     }
 
     fn msg(&self) -> Vec<Vec<u8>> {
         vec![self.2.clone()]
     }
+
+    fn delay(&self) -> Option<Duration> {
+        Some(self.1)
+    }
 }
 
 #[derive(Debug)]
 pub enum ConnMsg {
     Close,
+    /// Stop receiving messages (RDY 0, CLS) and close once the handlers finished the
+    /// ones in flight, or after [drain_timeout](struct.Config.html#structfield.drain_timeout).
+    Drain,
     Connect(String),
     GetIsConnected,
 }
@@ -207,8 +276,34 @@ pub struct MsgTimeInfo {
     pub last_time_sent: u32,
 }
 
+#[derive(Debug)]
+pub struct ReconnectInfo {
+    pub addr: String,
+    pub attempt: u32,
+}
+
+#[derive(Debug)]
+pub struct AuthInfo {
+    pub addr: String,
+    pub resp: AuthResp,
+}
+
+#[derive(Debug)]
+pub struct BackoffInfo {
+    /// Failures not yet compensated by successes.
+    pub level: u32,
+    /// Time with RDY 0 before probing with RDY 1.
+    pub duration: Duration,
+}
+
 #[derive(Debug)]
 pub enum ConnMsgInfo {
     IsConnected(ConnInfo),
     MsgInfo(MsgTimeInfo),
+    Reconnect(ReconnectInfo),
+    Auth(AuthInfo),
+    /// Handlers failed, RDY 0 on every connection.
+    Backoff(BackoffInfo),
+    /// Backoff ended, RDY given back.
+    Resume,
 }
@@ -0,0 +1,257 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::error::{ConnError, PubError};
+use crate::publisher::Publisher;
+
+const UNHEALTHY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How [PublisherPool](struct.PublisherPool.html) picks the nsqd of the next publish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Selection {
+    /// Every nsqd in turn.
+    RoundRobin,
+    /// The nsqd with the fewest publishes waiting for a response.
+    LeastPending,
+}
+
+struct Node {
+    addr: String,
+    publisher: Publisher,
+    pending: AtomicUsize,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Node {
+    fn is_healthy(&self, now: Instant) -> bool {
+        match *self.unhealthy_until.lock().unwrap() {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+
+    fn publish<F>(&self, f: &F) -> Result<(), PubError>
+    where
+        F: Fn(&Publisher) -> Result<(), PubError>,
+    {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let res = f(&self.publisher);
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+}
+
+/// Publish on a set of nsqd, moving to the next one when a node fails.
+///
+/// A node answering with a retryable error (see
+/// [is_retryable](enum.PubError.html#method.is_retryable)) is left aside for
+/// [unhealthy_timeout](#method.unhealthy_timeout) and the publish is made again on
+/// another node. Nodes left aside are only tried once every other one failed.
+/// ```no-run
+/// use nsq_client::{Config, PublisherPool, Selection};
+///
+/// fn main() {
+///     let pool = PublisherPool::connect(vec!["nsqd-1:4150", "nsqd-2:4150"], Config::new(), None)
+///         .unwrap()
+///         .selection(Selection::LeastPending);
+///     pool.publish("topic", b"hello".to_vec()).unwrap();
+/// }
+/// ```
+pub struct PublisherPool {
+    nodes: Vec<Node>,
+    next: AtomicUsize,
+    selection: Selection,
+    unhealthy_timeout: Duration,
+}
+
+impl PublisherPool {
+    /// Connect a [Publisher](struct.Publisher.html) to every nsqd address.
+    pub fn connect<S: Into<String>>(
+        addrs: Vec<S>,
+        config: Config,
+        secret: Option<String>,
+    ) -> Result<PublisherPool, ConnError> {
+        let mut nodes = Vec::new();
+        for addr in addrs {
+            let addr = addr.into();
+            let publisher = Publisher::connect(addr.clone(), config.clone(), secret.clone())?;
+            nodes.push(Node {
+                addr,
+                publisher,
+                pending: AtomicUsize::new(0),
+                unhealthy_until: Mutex::new(None),
+            });
+        }
+        if nodes.is_empty() {
+            return Err(ConnError::Error("no nsqd address given".to_owned()));
+        }
+        Ok(PublisherPool {
+            nodes,
+            next: AtomicUsize::new(0),
+            selection: Selection::RoundRobin,
+            unhealthy_timeout: UNHEALTHY_TIMEOUT,
+        })
+    }
+
+    /// Change how the node of each publish is picked (default: round robin).
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Change how long a failing node is left aside (default: 10 seconds).
+    pub fn unhealthy_timeout(mut self, timeout: Duration) -> Self {
+        self.unhealthy_timeout = timeout;
+        self
+    }
+
+    /// Publish a message on topic (PUB).
+    pub fn publish<T: Into<String>>(&self, topic: T, body: Vec<u8>) -> Result<(), PubError> {
+        let topic = topic.into();
+        self.retry(|publisher| publisher.publish(topic.clone(), body.clone()))
+    }
+
+    /// Publish many messages on topic at once (MPUB).
+    pub fn mpub<T: Into<String>>(&self, topic: T, bodies: Vec<Vec<u8>>) -> Result<(), PubError> {
+        let topic = topic.into();
+        self.retry(|publisher| publisher.mpub(topic.clone(), bodies.clone()))
+    }
+
+    /// Publish a message delivered after delay (DPUB).
+    pub fn dpub<T: Into<String>>(
+        &self,
+        topic: T,
+        delay: Duration,
+        body: Vec<u8>,
+    ) -> Result<(), PubError> {
+        let topic = topic.into();
+        self.retry(|publisher| publisher.dpub(topic.clone(), delay, body.clone()))
+    }
+
+    /// Close every connection.
+    pub fn close(&self) {
+        for node in self.nodes.iter() {
+            node.publisher.close();
+        }
+    }
+
+    // nodes in the order they are tried, the ones left aside last.
+    fn order(&self) -> Vec<&Node> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.nodes.len();
+        let mut nodes: Vec<&Node> = (0..len).map(|i| &self.nodes[(start + i) % len]).collect();
+        if self.selection == Selection::LeastPending {
+            // stable: nodes as busy are still taken in turn.
+            nodes.sort_by_key(|node| node.pending.load(Ordering::SeqCst));
+        }
+        let now = Instant::now();
+        nodes.sort_by_key(|node| !node.is_healthy(now));
+        nodes
+    }
+
+    fn retry<F>(&self, f: F) -> Result<(), PubError>
+    where
+        F: Fn(&Publisher) -> Result<(), PubError>,
+    {
+        let mut last_err = PubError::Closed;
+        for node in self.order() {
+            let err = match node.publish(&f) {
+                Ok(()) => {
+                    *node.unhealthy_until.lock().unwrap() = None;
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            if !err.is_retryable() {
+                return Err(err);
+            }
+            warn!(
+                "[{}] publish failed, trying another nsqd: {}",
+                node.addr, err
+            );
+            *node.unhealthy_until.lock().unwrap() = Some(Instant::now() + self.unhealthy_timeout);
+            last_err = err;
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publisher::tests::nsqd;
+
+    #[test]
+    fn failover() {
+        let (failing, failing_handle) = nsqd(1, |_| Some("E_PUB_FAILED failed"));
+        let (working, working_handle) = nsqd(1, |cmd| {
+            if cmd == "PUB bad" {
+                return Some("E_BAD_TOPIC bad");
+            }
+            None
+        });
+        let pool = PublisherPool::connect(vec![failing, working], Config::default(), None).unwrap();
+        for _ in 0..3 {
+            pool.publish("t", b"msg".to_vec()).unwrap();
+        }
+        // refused by nsqd, the other node would refuse it too.
+        match pool.publish("bad", b"msg".to_vec()) {
+            Err(PubError::Nsqd(ConnError::BadTopic(_))) => {}
+            res => panic!("unexpected publish result {:?}", res),
+        }
+        drop(pool);
+        assert_eq!(failing_handle.join().unwrap(), ["PUB t"]);
+        assert_eq!(
+            working_handle.join().unwrap(),
+            ["PUB t", "PUB t", "PUB t", "PUB bad"]
+        );
+    }
+
+    #[test]
+    fn least_pending() {
+        let (first, first_handle) = nsqd(1, |_| None);
+        let (second, second_handle) = nsqd(1, |_| None);
+        let pool = PublisherPool::connect(vec![first, second], Config::default(), None)
+            .unwrap()
+            .selection(Selection::LeastPending);
+        // nodes as busy are taken in turn.
+        for _ in 0..4 {
+            pool.publish("t", b"msg".to_vec()).unwrap();
+        }
+        pool.nodes[0].pending.fetch_add(1, Ordering::SeqCst);
+        for _ in 0..2 {
+            pool.publish("t", b"msg".to_vec()).unwrap();
+        }
+        pool.nodes[0].pending.fetch_sub(1, Ordering::SeqCst);
+        drop(pool);
+        assert_eq!(first_handle.join().unwrap().len(), 2);
+        assert_eq!(second_handle.join().unwrap().len(), 4);
+    }
+}
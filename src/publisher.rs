@@ -0,0 +1,272 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::error;
+
+use crate::batch::Batcher;
+use crate::client::{Client, Route, SessionCloser};
+use crate::config::Config;
+use crate::error::{ConnError, PubError};
+use crate::msgs::{check_delay, Cmd, Dpub, Mpub, NsqCmd, Pub};
+
+// publish waiting to be written on the connection.
+pub(crate) struct PubRequest {
+    pub cmd: Cmd,
+    pub reply: Sender<Result<(), PubError>>,
+}
+
+struct Inner {
+    config: Config,
+    pub_s: Sender<PubRequest>,
+    route: Route,
+    closer: SessionCloser,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.closer.close();
+    }
+}
+
+/// Publish messages on a single nsqd and wait for its acknowledgement.
+///
+/// Clones share the same connection and can be used from many threads at once,
+/// every publish is answered in the order it has been written. The connection is
+/// opened again when lost, publishes made meanwhile fail with
+/// [ConnectionLost](enum.PubError.html#variant.ConnectionLost), and closed once
+/// every clone is dropped.
+/// ```no-run
+/// use nsq_client::{Config, Publisher};
+///
+/// fn main() {
+///     let publisher = Publisher::connect("127.0.0.1:4150", Config::new(), None).unwrap();
+///     publisher.publish("topic", b"hello".to_vec()).unwrap();
+///     publisher.mpub("topic", vec![b"one".to_vec(), b"two".to_vec()]).unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Publisher {
+    inner: Arc<Inner>,
+}
+
+impl Publisher {
+    /// Connect to nsqd, the handshake goes on in the background.
+    pub fn connect<S: Into<String>>(
+        addr: S,
+        config: Config,
+        secret: Option<S>,
+    ) -> Result<Publisher, ConnError> {
+        // no channel and topic means producer.
+        let (_in_s, in_r) = channel::unbounded();
+        let (info_s, _info_r) = channel::unbounded();
+        let mut client = Client::new(
+            String::new(),
+            String::new(),
+            addr.into(),
+            config.clone(),
+            secret.map(Into::into),
+            1,
+            0,
+            in_r,
+            info_s,
+        );
+        let (pub_s, pub_r): (Sender<PubRequest>, Receiver<PubRequest>) = channel::unbounded();
+        let (mut session, closer, route) = client.publisher(pub_r)?;
+        thread::spawn(move || {
+            if let Err(e) = session.run() {
+                error!("publisher stopped: {}", e);
+            }
+        });
+        Ok(Publisher {
+            inner: Arc::new(Inner {
+                config,
+                pub_s,
+                route,
+                closer,
+            }),
+        })
+    }
+
+    /// Publish a message on topic (PUB).
+    pub fn publish<T: Into<String>>(&self, topic: T, body: Vec<u8>) -> Result<(), PubError> {
+        self.send(Pub(topic.into(), body))
+    }
+
+    /// Publish many messages on topic at once (MPUB).
+    pub fn mpub<T: Into<String>>(&self, topic: T, bodies: Vec<Vec<u8>>) -> Result<(), PubError> {
+        self.send(Mpub(topic.into(), bodies))
+    }
+
+    /// Publish a message delivered after delay (DPUB).
+    ///
+    /// Delays longer than [max_req_timeout](struct.Config.html#structfield.max_req_timeout)
+    /// are refused with [PubError::Delay](enum.PubError.html#variant.Delay).
+    pub fn dpub<T: Into<String>>(
+        &self,
+        topic: T,
+        delay: Duration,
+        body: Vec<u8>,
+    ) -> Result<(), PubError> {
+        self.send(Dpub(topic.into(), delay, body))
+    }
+
+    /// Publish single messages in MPUB batches, see [Batcher](struct.Batcher.html).
+    pub fn batcher(&self) -> Batcher {
+        Batcher::new(self.clone(), &self.inner.config)
+    }
+
+    /// Close the connection, pending publishes are answered with an error.
+    pub fn close(&self) {
+        self.inner.closer.close();
+    }
+
+    fn send<C: NsqCmd>(&self, cmd: C) -> Result<(), PubError> {
+        let max_req_timeout = Duration::from_millis(self.inner.config.max_req_timeout);
+        check_delay(&cmd, max_req_timeout).map_err(PubError::Delay)?;
+        // the request is dropped with the session when it stops.
+        self.queue(cmd).recv().unwrap_or(Err(PubError::Closed))
+    }
+
+    // queue the publish, the response comes on the receiver returned.
+    pub(crate) fn queue<C: NsqCmd>(&self, cmd: C) -> Receiver<Result<(), PubError>> {
+        let (reply, reply_r) = channel::bounded(1);
+        let req = PubRequest {
+            cmd: cmd.as_cmd(),
+            reply,
+        };
+        if self.inner.pub_s.send(req).is_ok() {
+            self.inner.route.wake();
+        }
+        reply_r
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::codec::tests::{frame, read_body, NSQD_CONFIG};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    // error frame nsqd answers to a command, the connection is closed after it.
+    pub(crate) type Refuse = fn(&str) -> Option<&'static str>;
+
+    // serve a connection until the client closes it or a publish is refused.
+    fn serve(stream: TcpStream, refuse: Refuse, cmds: &mut Vec<String>) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let cmd = line.trim_end().to_owned();
+            let body = read_body(&mut reader);
+            if cmd == "IDENTIFY" {
+                writer.write_all(&frame(0, NSQD_CONFIG.as_bytes())).unwrap();
+                continue;
+            }
+            if cmd == "MPUB t" {
+                // a single message keeps the MPUB framing.
+                assert_eq!(body, b"\x00\x00\x00\x01\x00\x00\x00\x03one");
+            }
+            cmds.push(cmd.clone());
+            if let Some(err) = refuse(&cmd) {
+                writer.write_all(&frame(1, err.as_bytes())).unwrap();
+                return;
+            }
+            writer.write_all(&frame(0, b"OK")).unwrap();
+        }
+    }
+
+    // nsqd accepting connections times, returns the publishes received.
+    pub(crate) fn nsqd(
+        connections: usize,
+        refuse: Refuse,
+    ) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let mut cmds = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                serve(stream, refuse, &mut cmds);
+            }
+            cmds
+        });
+        (addr, handle)
+    }
+
+    fn bad_topic(cmd: &str) -> Option<&'static str> {
+        if cmd == "PUB bad" {
+            return Some("E_BAD_TOPIC bad");
+        }
+        None
+    }
+
+    #[test]
+    fn publish_and_acknowledge() {
+        let (addr, handle) = nsqd(2, bad_topic);
+        let publisher = Publisher::connect(addr, Config::default(), None).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let publisher = publisher.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        publisher.publish("t", b"msg".to_vec()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        publisher.mpub("t", vec![b"one".to_vec()]).unwrap();
+        match publisher.publish("bad", b"msg".to_vec()) {
+            Err(PubError::Nsqd(ConnError::BadTopic(desc))) => assert_eq!(desc, "bad"),
+            res => panic!("unexpected publish result {:?}", res),
+        }
+        // a publish made while nsqd closes the connection is refused.
+        let mut res = Err(PubError::ConnectionLost);
+        while let Err(PubError::ConnectionLost) = res {
+            res = publisher.dpub("t", Duration::from_secs(1), b"later".to_vec());
+        }
+        res.unwrap();
+        match publisher.dpub("t", Duration::from_secs(7200), b"too late".to_vec()) {
+            Err(PubError::Delay(e)) => assert_eq!(e.max, Duration::from_secs(3600)),
+            res => panic!("unexpected publish result {:?}", res),
+        }
+        drop(publisher);
+        let cmds = handle.join().unwrap();
+        assert_eq!(cmds.len(), 43);
+        assert_eq!(cmds.iter().filter(|cmd| *cmd == "PUB t").count(), 40);
+        assert_eq!(cmds[40..], ["MPUB t", "PUB bad", "DPUB t 1000"]);
+    }
+}
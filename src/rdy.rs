@@ -0,0 +1,505 @@
+// MIT License
+//
+// Copyright (c) 2019-2021 Alessandro Cresto Miseroglio <alex179ohm@gmail.com>
+// Copyright (c) 2019-2021 Tangram Technologies S.R.L. <https://tngrm.io>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::msgs::{BackoffInfo, ConnMsgInfo};
+use crossbeam::channel::Sender;
+use log::{debug, info};
+use mio::{Ready, SetReadiness};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Interval between two RDY redistributions.
+pub const RDY_REDISTRIBUTE_INTERVAL: Duration = Duration::from_secs(5);
+
+// connections without messages for this long give their RDY back to the budget.
+const LOW_RDY_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct ConnRdy {
+    // last RDY count sent (or about to be sent) to nsqd.
+    rdy: u32,
+    in_flight: u32,
+    last_msg: Instant,
+    // RDY decided by a redistribution, not yet sent by the connection.
+    pending: Option<u32>,
+    waker: SetReadiness,
+}
+
+#[derive(Debug, Default)]
+struct Budget {
+    conns: HashMap<String, ConnRdy>,
+    // rotation offset used to hand RDY 1 to idle connections.
+    next: usize,
+    need_redistribute: bool,
+    backoff: Backoff,
+}
+
+// RDY 0 everywhere while waiting, then RDY 1 on a single connection to probe.
+#[derive(Debug, Default)]
+struct Backoff {
+    // failures not yet compensated by successes, 0 when not backing off.
+    level: u32,
+    // end of the wait.
+    until: Option<Instant>,
+    // connection probing with RDY 1.
+    probe: Option<String>,
+}
+
+impl Budget {
+    fn total_rdy(&self) -> u32 {
+        self.conns.values().map(|c| c.rdy).sum()
+    }
+}
+
+/// Split `max_in_flight` among the connections of a client.
+///
+/// Every connection gets `max_in_flight / connections` (at least 1), the total
+/// RDY never exceeds `max_in_flight`. When there are more connections than
+/// `max_in_flight`, idle connections are set to RDY 0 and RDY 1 is rotated among them.
+///
+/// Failures of the handlers put every connection in backoff: RDY 0 for
+/// `multiplier * 2^failures` (up to `max_backoff`), then RDY 1 on a single connection.
+/// Every success takes a failure back, the RDY are given back once none is left.
+#[derive(Debug)]
+pub struct RdyBalancer {
+    max_in_flight: u32,
+    budget: Mutex<Budget>,
+    backoff_multiplier: Duration,
+    max_backoff: Duration,
+    info: Option<Sender<ConnMsgInfo>>,
+}
+
+impl RdyBalancer {
+    pub fn new(max_in_flight: u32) -> RdyBalancer {
+        RdyBalancer {
+            max_in_flight,
+            budget: Mutex::new(Budget::default()),
+            backoff_multiplier: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(120),
+            info: None,
+        }
+    }
+
+    /// Change the backoff durations, events are sent on info.
+    pub fn backoff(
+        mut self,
+        multiplier: Duration,
+        max: Duration,
+        info: Sender<ConnMsgInfo>,
+    ) -> RdyBalancer {
+        self.backoff_multiplier = multiplier;
+        self.max_backoff = max;
+        self.info = Some(info);
+        self
+    }
+
+    /// Register a connection ready to receive messages, returns its initial RDY.
+    ///
+    /// `waker` is used to wake up the connection when a redistribution changes its RDY.
+    pub fn add(&self, addr: &str, waker: SetReadiness) -> u32 {
+        let mut budget = self.budget.lock().unwrap();
+        budget.conns.insert(
+            addr.to_owned(),
+            ConnRdy {
+                rdy: 0,
+                in_flight: 0,
+                last_msg: Instant::now(),
+                pending: None,
+                waker,
+            },
+        );
+        if budget.conns.len() as u32 > self.max_in_flight {
+            budget.need_redistribute = true;
+        }
+        self.rebalance(&mut budget);
+        // the first RDY is sent by the connection itself.
+        let conn = budget.conns.get_mut(addr).unwrap();
+        conn.pending = None;
+        conn.rdy
+    }
+
+    /// Give the RDY of a closed connection back to the budget.
+    pub fn remove(&self, addr: &str) {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.conns.remove(addr).is_some() {
+            budget.need_redistribute = true;
+            self.rebalance(&mut budget);
+        }
+        if budget.backoff.probe.as_deref() == Some(addr) {
+            // probe again on another connection.
+            budget.backoff.probe = None;
+            budget.backoff.until = Some(Instant::now());
+        }
+    }
+
+    /// A handler failed on a message, back off or back off longer.
+    pub fn failure(&self) {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.backoff.until.is_some() {
+            // messages in flight before the backoff started.
+            return;
+        }
+        budget.backoff.level += 1;
+        self.start_backoff(&mut budget);
+    }
+
+    /// A handler succeeded on a message, resume once the failures are compensated.
+    pub fn success(&self) {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.backoff.level == 0 || budget.backoff.until.is_some() {
+            return;
+        }
+        budget.backoff.level -= 1;
+        if budget.backoff.level > 0 {
+            self.start_backoff(&mut budget);
+            return;
+        }
+        info!("backoff ended, resuming");
+        budget.backoff.probe = None;
+        let addrs: Vec<String> = budget.conns.keys().cloned().collect();
+        for addr in addrs {
+            if let Some(rdy) = self.update(&mut budget, &addr) {
+                set_rdy(budget.conns.get_mut(&addr).unwrap(), rdy);
+            }
+        }
+        self.send_info(ConnMsgInfo::Resume);
+    }
+
+    /// Start the RDY 1 probe once the backoff wait is over, returns the time left to wait.
+    pub fn backoff_tick(&self) -> Option<Duration> {
+        let mut budget = self.budget.lock().unwrap();
+        let until = budget.backoff.until?;
+        let now = Instant::now();
+        if now < until {
+            return Some(until - now);
+        }
+        let mut addrs: Vec<String> = budget.conns.keys().cloned().collect();
+        if addrs.is_empty() {
+            return None;
+        }
+        addrs.sort();
+        let addr = addrs[budget.next % addrs.len()].clone();
+        budget.next += 1;
+        debug!("[{}] backoff probe, RDY 1", addr);
+        set_rdy(budget.conns.get_mut(&addr).unwrap(), 1);
+        budget.backoff.until = None;
+        budget.backoff.probe = Some(addr);
+        None
+    }
+
+    fn start_backoff(&self, budget: &mut Budget) {
+        let level = budget.backoff.level;
+        let duration = self
+            .backoff_multiplier
+            .checked_mul(2u32.saturating_pow(level))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        info!("backing off for {:?} (level {})", duration, level);
+        budget.backoff.until = Some(Instant::now() + duration);
+        budget.backoff.probe = None;
+        for conn in budget.conns.values_mut() {
+            if conn.rdy > 0 {
+                set_rdy(conn, 0);
+            }
+        }
+        self.send_info(ConnMsgInfo::Backoff(BackoffInfo { level, duration }));
+    }
+
+    fn send_info(&self, info: ConnMsgInfo) {
+        if let Some(s) = self.info.as_ref() {
+            let _ = s.send(info);
+        }
+    }
+
+    /// Called when the connection received messages, returns the RDY to send if it changed.
+    pub fn on_msg(&self, addr: &str, last_msg: Instant, in_flight: u32) -> Option<u32> {
+        let mut budget = self.budget.lock().unwrap();
+        let conn = budget.conns.get_mut(addr)?;
+        conn.last_msg = last_msg;
+        conn.in_flight = in_flight;
+        self.update(&mut budget, addr)
+    }
+
+    /// RDY set by the last redistribution for the connection.
+    pub fn take_pending(&self, addr: &str) -> Option<u32> {
+        let mut budget = self.budget.lock().unwrap();
+        budget.conns.get_mut(addr).and_then(|c| c.pending.take())
+    }
+
+    /// Take RDY back from idle connections and rotate RDY 1 among the starving ones.
+    pub fn redistribute(&self) {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.backoff.level > 0 {
+            return;
+        }
+        if budget.conns.len() as u32 > self.max_in_flight {
+            budget.need_redistribute = true;
+        }
+        if !budget.need_redistribute {
+            return;
+        }
+        budget.need_redistribute = false;
+        let mut addrs: Vec<String> = budget.conns.keys().cloned().collect();
+        addrs.sort();
+        for addr in addrs.iter() {
+            let conn = budget.conns.get_mut(addr).unwrap();
+            if conn.rdy > 0 && conn.in_flight == 0 && conn.last_msg.elapsed() > LOW_RDY_IDLE_TIMEOUT
+            {
+                debug!("[{}] idle connection, RDY 0", addr);
+                set_rdy(conn, 0);
+            }
+        }
+        let mut available = self.max_in_flight.saturating_sub(budget.total_rdy());
+        if addrs.is_empty() {
+            return;
+        }
+        let start = budget.next % addrs.len();
+        addrs.rotate_left(start);
+        budget.next = start + 1;
+        for addr in addrs {
+            if available == 0 {
+                break;
+            }
+            let conn = budget.conns.get_mut(&addr).unwrap();
+            if conn.rdy == 0 {
+                debug!("[{}] rotating RDY 1", addr);
+                set_rdy(conn, 1);
+                available -= 1;
+            }
+        }
+    }
+
+    fn per_conn_max_in_flight(&self, conns: usize) -> u32 {
+        if conns == 0 {
+            return self.max_in_flight;
+        }
+        (self.max_in_flight / conns as u32).max(1)
+    }
+
+    // move every connection to its share of the budget after a connection came or went:
+    // the ones above it first, then the ones below it with what is left.
+    fn rebalance(&self, budget: &mut Budget) {
+        if budget.backoff.level > 0 {
+            return;
+        }
+        let count = self.per_conn_max_in_flight(budget.conns.len());
+        let mut addrs: Vec<String> = budget.conns.keys().cloned().collect();
+        addrs.sort();
+        for addr in addrs.iter() {
+            let conn = budget.conns.get_mut(addr).unwrap();
+            if conn.rdy > count {
+                set_rdy(conn, count);
+            }
+        }
+        let mut available = self.max_in_flight.saturating_sub(budget.total_rdy());
+        for addr in addrs.iter() {
+            if available == 0 {
+                break;
+            }
+            let conn = budget.conns.get_mut(addr).unwrap();
+            if conn.rdy < count {
+                let rdy = count.min(conn.rdy + available);
+                available -= rdy - conn.rdy;
+                set_rdy(conn, rdy);
+            }
+        }
+    }
+
+    // move the connection RDY to its share of the budget, returns the new RDY if it changed.
+    fn update(&self, budget: &mut Budget, addr: &str) -> Option<u32> {
+        if budget.backoff.level > 0 {
+            // RDY is driven by the backoff.
+            return None;
+        }
+        let count = self.per_conn_max_in_flight(budget.conns.len());
+        let others = budget.total_rdy() - budget.conns[addr].rdy;
+        let max_possible = self.max_in_flight.saturating_sub(others);
+        let conn = budget.conns.get_mut(addr).unwrap();
+        if max_possible == 0 {
+            // no budget left, wait for a redistribution.
+            if conn.rdy == 0 {
+                budget.need_redistribute = true;
+            }
+            return None;
+        }
+        let count = count.min(max_possible);
+        if count == conn.rdy {
+            return None;
+        }
+        conn.rdy = count;
+        conn.pending = None;
+        Some(count)
+    }
+}
+
+fn set_rdy(conn: &mut ConnRdy, rdy: u32) {
+    conn.rdy = rdy;
+    conn.pending = Some(rdy);
+    let _ = conn.waker.set_readiness(Ready::writable());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel;
+    use mio::Registration;
+    use std::thread;
+
+    fn with_conns(max_in_flight: u32, conns: &[&str]) -> (RdyBalancer, Vec<Registration>) {
+        let balancer = RdyBalancer::new(max_in_flight);
+        let registrations = conns
+            .iter()
+            .map(|addr| {
+                let (registration, waker) = Registration::new2();
+                balancer.add(addr, waker);
+                registration
+            })
+            .collect();
+        (balancer, registrations)
+    }
+
+    // RDY of the connections, ordered by address.
+    fn rdys(balancer: &RdyBalancer) -> Vec<u32> {
+        let budget = balancer.budget.lock().unwrap();
+        let mut conns: Vec<(&String, u32)> =
+            budget.conns.iter().map(|(addr, c)| (addr, c.rdy)).collect();
+        conns.sort();
+        conns.into_iter().map(|(_, rdy)| rdy).collect()
+    }
+
+    #[test]
+    fn even_split() {
+        let (balancer, _registrations) = with_conns(10, &["a"]);
+        assert_eq!(rdys(&balancer), [10]);
+        let (_registration, waker) = Registration::new2();
+        // the connection already there gives half of its RDY.
+        assert_eq!(balancer.add("b", waker), 5);
+        assert_eq!(balancer.take_pending("a"), Some(5));
+        assert_eq!(balancer.take_pending("b"), None);
+        assert_eq!(rdys(&balancer), [5, 5]);
+    }
+
+    #[test]
+    fn split_remainder() {
+        let (balancer, _registrations) = with_conns(10, &["a", "b", "c"]);
+        // the remainder isn't given, the total never exceeds max_in_flight.
+        assert_eq!(rdys(&balancer), [3, 3, 3]);
+        let (balancer, _registrations) = with_conns(3, &["a", "b"]);
+        assert_eq!(rdys(&balancer), [1, 1]);
+    }
+
+    #[test]
+    fn rotate_rdy_1() {
+        let (balancer, _registrations) = with_conns(2, &["a", "b", "c"]);
+        assert_eq!(rdys(&balancer), [1, 1, 0]);
+        let idle = Instant::now() - LOW_RDY_IDLE_TIMEOUT - Duration::from_secs(1);
+        let mut starved = vec![0; 3];
+        for _ in 0..3 {
+            for addr in ["a", "b", "c"].iter() {
+                balancer.on_msg(addr, idle, 0);
+            }
+            balancer.redistribute();
+            let rdys = rdys(&balancer);
+            assert_eq!(rdys.iter().sum::<u32>(), 2);
+            for (i, rdy) in rdys.iter().enumerate() {
+                if *rdy == 0 {
+                    starved[i] += 1;
+                }
+            }
+        }
+        // every connection had its turn with RDY 1.
+        assert_eq!(starved, [1, 1, 1]);
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let (balancer, _registrations) = with_conns(10, &["a", "b"]);
+        balancer.take_pending("a");
+        let (_registration, waker) = Registration::new2();
+        assert_eq!(balancer.add("c", waker), 3);
+        assert_eq!(balancer.take_pending("a"), Some(3));
+        assert_eq!(balancer.take_pending("b"), Some(3));
+        // the RDY of a closed connection go to the others.
+        balancer.remove("a");
+        assert_eq!(rdys(&balancer), [5, 5]);
+        assert_eq!(balancer.take_pending("b"), Some(5));
+        assert_eq!(balancer.take_pending("c"), Some(5));
+        balancer.remove("b");
+        assert_eq!(rdys(&balancer), [10]);
+    }
+
+    fn backoff_level(info: ConnMsgInfo) -> u32 {
+        match info {
+            ConnMsgInfo::Backoff(backoff) => {
+                assert_eq!(backoff.duration, Duration::from_millis(15));
+                backoff.level
+            }
+            info => panic!("unexpected info {:?}", info),
+        }
+    }
+
+    #[test]
+    fn backoff_and_resume() {
+        let (info_s, info_r) = channel::unbounded();
+        let balancer = RdyBalancer::new(10).backoff(
+            Duration::from_millis(10),
+            Duration::from_millis(15),
+            info_s,
+        );
+        let (_registration, waker) = Registration::new2();
+        assert_eq!(balancer.add("nsqd:4150", waker), 10);
+
+        balancer.failure();
+        assert_eq!(balancer.take_pending("nsqd:4150"), Some(0));
+        assert_eq!(backoff_level(info_r.try_recv().unwrap()), 1);
+        // messages in flight before the backoff don't count.
+        balancer.failure();
+        balancer.success();
+        assert!(info_r.try_recv().is_err());
+        assert!(balancer.backoff_tick().is_some());
+
+        // failed probe: longer backoff.
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(balancer.backoff_tick(), None);
+        assert_eq!(balancer.take_pending("nsqd:4150"), Some(1));
+        balancer.failure();
+        assert_eq!(balancer.take_pending("nsqd:4150"), Some(0));
+        assert_eq!(backoff_level(info_r.try_recv().unwrap()), 2);
+
+        // successful probes take the failures back.
+        for level in [1, 0] {
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(balancer.backoff_tick(), None);
+            assert_eq!(balancer.take_pending("nsqd:4150"), Some(1));
+            balancer.success();
+            if level > 0 {
+                assert_eq!(balancer.take_pending("nsqd:4150"), Some(0));
+                assert_eq!(backoff_level(info_r.try_recv().unwrap()), level);
+            }
+        }
+        assert_eq!(balancer.take_pending("nsqd:4150"), Some(10));
+        assert!(matches!(info_r.try_recv(), Ok(ConnMsgInfo::Resume)));
+        assert_eq!(balancer.backoff_tick(), None);
+    }
+}
@@ -1,25 +1,328 @@
 #[cfg(feature = "async")]
 use crate::async_context::ContextAsync;
 use crate::client::Context;
-use crate::msgs::Msg;
-use crate::msgs::Touch;
+use crate::dead_letter::DeadLetter;
+use crate::error::HandlerError;
+use crate::msgs::{Fin, Msg};
+use log::{error, warn};
+use std::any::Any;
 #[cfg(feature = "async")]
 use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
 
-#[cfg(not(feature = "async"))]
 pub trait Consumer: Clone + Sync + Send + 'static {
     fn on_msg(&mut self, msg: Msg, ctx: &mut Context);
+    /// Called instead of [on_msg](#tymethod.on_msg) once a message was delivered more
+    /// than [max_attemps](#method.max_attemps) times, finishes it by default.
     fn on_max_attemps(&mut self, msg: Msg, ctx: &mut Context) {
-        ctx.send(Touch(msg.id));
+        warn!(
+            "message {} finished after {} attempts: {}",
+            msg.id,
+            msg.attemps,
+            String::from_utf8_lossy(&msg.body)
+        );
+        ctx.send(Fin(msg.id));
+    }
+    /// Attempts of a message before it goes to [on_max_attemps](#method.on_max_attemps),
+    /// 0 for no limit. None keeps the one given to [Client::new](struct.Client.html#method.new).
+    fn max_attemps(&self) -> Option<u16> {
+        None
+    }
+    fn on_close(&mut self, _ctx: &mut Context) {}
+}
+
+// hand the message to the consumer, or to on_max_attemps past the limit.
+pub(crate) fn deliver<C: Consumer>(
+    consumer: &mut C,
+    msg: Msg,
+    max_attemps: u16,
+    ctx: &mut Context,
+) {
+    if max_attemps > 0 && msg.attemps > max_attemps {
+        consumer.on_max_attemps(msg, ctx);
+    } else {
+        consumer.on_msg(msg, ctx);
+    }
+}
+
+/// How the consumer answers nsqd once a [Handler](trait.Handler.html) is done with a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Disposition {
+    /// FIN, the message is processed.
+    Finish,
+    /// REQ, the message is delivered again after the delay.
+    Requeue(Duration),
+    /// REQ with a delay growing with the attempts of the message, see
+    /// [requeue_delay](struct.Config.html#structfield.requeue_delay).
+    RequeueWithBackoff,
+    /// TOUCH, the handler keeps the message and answers later through the context.
+    Touch,
+}
+
+impl From<Result<(), HandlerError>> for Disposition {
+    fn from(res: Result<(), HandlerError>) -> Disposition {
+        res.into_disposition().0
+    }
+}
+
+/// Outcome of a [Handler](trait.Handler.html), implemented by
+/// [Disposition](enum.Disposition.html) and `Result<(), HandlerError>`.
+pub trait IntoDisposition {
+    /// The disposition and the error of the handler, if it failed.
+    fn into_disposition(self) -> (Disposition, Option<HandlerError>);
+}
+
+impl IntoDisposition for Disposition {
+    fn into_disposition(self) -> (Disposition, Option<HandlerError>) {
+        (self, None)
     }
-    fn on_close(&mut self, ctx: &mut Context) {}
 }
 
+impl IntoDisposition for Result<(), HandlerError> {
+    fn into_disposition(self) -> (Disposition, Option<HandlerError>) {
+        match self {
+            Ok(()) => (Disposition::Finish, None),
+            Err(e) => match e.delay() {
+                Some(delay) => (Disposition::Requeue(delay), Some(e)),
+                None => (Disposition::RequeueWithBackoff, Some(e)),
+            },
+        }
+    }
+}
+
+/// Handler which doesn't answer nsqd itself, started with
+/// [Client::spawn_handler](struct.Client.html#method.spawn_handler).
+///
+/// The consumer sends FIN, REQ or TOUCH from the outcome returned, either a
+/// [Disposition](enum.Disposition.html) or a `Result<(), HandlerError>`.
+/// A panicking handler has its message requeued with backoff.
+///
+/// With a [DeadLetter](struct.DeadLetter.html) topic, a message the handler fails on
+/// for its last allowed attempt is published there and finished.
+/// ```no-run
+/// use nsq_client::{Context, Handler, HandlerError, Msg};
+///
+/// #[derive(Clone)]
+/// struct MyReader;
+///
+/// impl Handler for MyReader {
+///     type Outcome = Result<(), HandlerError>;
+///
+///     fn handle(&mut self, msg: Msg, _ctx: &mut Context) -> Self::Outcome {
+///         let body = std::str::from_utf8(&msg.body)?;
+///         println!("{}", body);
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait Handler: Clone + Sync + Send + 'static {
+    type Outcome: IntoDisposition;
+    fn handle(&mut self, msg: Msg, ctx: &mut Context) -> Self::Outcome;
+    /// See [Consumer::on_max_attemps](trait.Consumer.html#method.on_max_attemps).
+    fn on_max_attemps(&mut self, msg: Msg, _ctx: &mut Context) -> Disposition {
+        warn!(
+            "message {} finished after {} attempts: {}",
+            msg.id,
+            msg.attemps,
+            String::from_utf8_lossy(&msg.body)
+        );
+        Disposition::Finish
+    }
+    /// See [Consumer::max_attemps](trait.Consumer.html#method.max_attemps).
+    fn max_attemps(&self) -> Option<u16> {
+        None
+    }
+    fn on_close(&mut self, _ctx: &mut Context) {}
+}
+
+// Consumer answering nsqd for a Handler.
+#[derive(Clone)]
+pub(crate) struct Disposer<H> {
+    handler: H,
+    // with the attempts of a message before it is dead-lettered, 0 for no limit.
+    dead_letter: Option<(DeadLetter, u16)>,
+}
+
+impl<H: Handler> Disposer<H> {
+    pub(crate) fn new(handler: H) -> Disposer<H> {
+        Disposer {
+            handler,
+            dead_letter: None,
+        }
+    }
+
+    pub(crate) fn dead_letter(mut self, dead_letter: DeadLetter, max_attemps: u16) -> Disposer<H> {
+        self.dead_letter = Some((dead_letter, max_attemps));
+        self
+    }
+}
+
+impl<H: Handler> Consumer for Disposer<H> {
+    fn on_msg(&mut self, msg: Msg, ctx: &mut Context) {
+        let id = msg.id;
+        let attemps = msg.attemps;
+        // the last attempt keeps the message for the dead-letter topic.
+        let last = match &self.dead_letter {
+            Some((_, max)) if *max > 0 && attemps >= *max => Some(msg.clone()),
+            _ => None,
+        };
+        let handler = &mut self.handler;
+        let (disposition, err) =
+            match panic::catch_unwind(AssertUnwindSafe(|| handler.handle(msg, ctx))) {
+                Ok(outcome) => outcome.into_disposition(),
+                Err(panic) => {
+                    error!("handler panicked on message {}, requeued", id);
+                    let err = HandlerError::new(panic_reason(&*panic));
+                    (Disposition::RequeueWithBackoff, Some(err))
+                }
+            };
+        match (&self.dead_letter, last, disposition) {
+            (Some((dead_letter, _)), Some(msg), Disposition::Requeue(_))
+            | (Some((dead_letter, _)), Some(msg), Disposition::RequeueWithBackoff) => {
+                let reason = err.as_ref().map_or("requeued", HandlerError::reason);
+                dead_letter.send(msg, reason, ctx);
+            }
+            _ => ctx.dispose(id, attemps, disposition),
+        }
+    }
+
+    fn on_max_attemps(&mut self, msg: Msg, ctx: &mut Context) {
+        if let Some((dead_letter, _)) = &self.dead_letter {
+            dead_letter.send(msg, "max attempts exceeded", ctx);
+            return;
+        }
+        let id = msg.id;
+        let attemps = msg.attemps;
+        let disposition = self.handler.on_max_attemps(msg, ctx);
+        ctx.dispose(id, attemps, disposition);
+    }
+
+    fn max_attemps(&self) -> Option<u16> {
+        self.handler.max_attemps()
+    }
+
+    fn on_close(&mut self, ctx: &mut Context) {
+        self.handler.on_close(ctx);
+    }
+}
+
+fn panic_reason(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(reason) => (*reason).to_owned(),
+        None => match panic.downcast_ref::<String>() {
+            Some(reason) => reason.clone(),
+            None => "handler panicked".to_owned(),
+        },
+    }
+}
+
+/// Handler of [AsyncClient](struct.AsyncClient.html), every message is handled in its own task.
+///
+/// Boxing is the simplest way to name the future:
+/// ```no-run
+/// use std::{future::Future, pin::Pin};
+/// use nsq_client::{AsyncConsumer, ContextAsync, Fin, Msg};
+///
+/// #[derive(Clone)]
+/// struct MyReader;
+///
+/// impl AsyncConsumer for MyReader {
+///     type Future = Pin<Box<dyn Future<Output = ()> + Send>>;
+///
+///     fn on_msg(&mut self, msg: Msg, ctx: ContextAsync) -> Self::Future {
+///         Box::pin(async move {
+///             ctx.send(Fin(msg.id));
+///         })
+///     }
+/// }
+/// ```
 #[cfg(feature = "async")]
-pub trait Consumer: Clone + Sync + Send + 'static {
-    type Output: Box<dyn Future<Output = ()>>;
-    fn handle(&mut self, msg: Msg, ctx: &mut ContextAsync) -> Self::Output;
-    fn on_max_attemps(&mut self, msg: Msg, ctx: &mut ContextAsync) -> Self::Output {
-        ctx.send(Touch(msg.id));
+pub trait AsyncConsumer: Clone + Sync + Send + 'static {
+    type Future: Future<Output = ()> + Send + 'static;
+    fn on_msg(&mut self, msg: Msg, ctx: ContextAsync) -> Self::Future;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::client::Route;
+    use crate::config::Config;
+    use bytes::Bytes;
+    use crossbeam::channel;
+    use mio::Registration;
+
+    #[derive(Clone)]
+    struct Outcomes;
+
+    impl Handler for Outcomes {
+        type Outcome = Result<(), HandlerError>;
+
+        fn handle(&mut self, msg: Msg, _ctx: &mut Context) -> Self::Outcome {
+            match &msg.body[..] {
+                b"ok" => Ok(()),
+                b"later" => Err(HandlerError::new("later").requeue_after(Duration::from_secs(5))),
+                b"panic" => panic!("handler failure"),
+                body => {
+                    String::from_utf8(body.to_vec())?;
+                    Err(HandlerError::new("failed"))
+                }
+            }
+        }
+    }
+
+    pub(crate) fn msg(id: &str, attemps: u16, body: &[u8]) -> Msg {
+        Msg {
+            timeout: 60000,
+            timestamp: 0,
+            attemps,
+            id: format!("{:0>16}", id).parse().unwrap(),
+            body: Bytes::from(body),
+        }
+    }
+
+    #[test]
+    fn dispose_outcomes() {
+        let (cmd_s, cmd_r) = channel::unbounded();
+        let (_registration, waker) = Registration::new2();
+        let config = Config::new().requeue_delay(1000).max_requeue_delay(2500);
+        let mut ctx = Context::new(Route::new(cmd_s, waker), &config);
+        let mut consumer = Disposer::new(Outcomes);
+        consumer.on_msg(msg("1", 1, b"ok"), &mut ctx);
+        consumer.on_msg(msg("2", 1, b"later"), &mut ctx);
+        consumer.on_msg(msg("3", 2, b"failed"), &mut ctx);
+        consumer.on_msg(msg("4", 3, b"\xff"), &mut ctx);
+        consumer.on_msg(msg("5", 1, b"panic"), &mut ctx);
+        let cmds: Vec<String> = cmd_r.try_iter().map(|cmd| cmd.cmd).collect();
+        assert_eq!(
+            cmds,
+            [
+                "FIN 0000000000000001",
+                "REQ 0000000000000002 5000",
+                "REQ 0000000000000003 2000",
+                "REQ 0000000000000004 2500",
+                "REQ 0000000000000005 1000"
+            ]
+        );
+    }
+
+    #[test]
+    fn max_attemps() {
+        let (cmd_s, cmd_r) = channel::unbounded();
+        let (_registration, waker) = Registration::new2();
+        let mut ctx = Context::new(Route::new(cmd_s, waker), &Config::new());
+        let mut consumer = Disposer::new(Outcomes);
+        deliver(&mut consumer, msg("1", 2, b"failed"), 2, &mut ctx);
+        deliver(&mut consumer, msg("2", 3, b"failed"), 2, &mut ctx);
+        deliver(&mut consumer, msg("3", 3, b"failed"), 0, &mut ctx);
+        let cmds: Vec<String> = cmd_r.try_iter().map(|cmd| cmd.cmd).collect();
+        assert_eq!(
+            cmds,
+            [
+                "REQ 0000000000000001 180000",
+                "FIN 0000000000000002",
+                "REQ 0000000000000003 270000"
+            ]
+        );
     }
 }
@@ -1,59 +1,345 @@
-use futures::{AsyncRead, AsyncWrite};
-pub trait State {}
+// Typestate handshake with nsqd, shared by the sync and async clients.
+//
+// No io happens here: every state writes the commands it needs into the buffer given
+// and moves on with the response of nsqd read by the caller. Upgrades of the stream
+// (tls, compression) are asked to the caller through Handshake::Tls and
+// Handshake::Compress which only give the next state once acknowledged.
+use bytes::BytesMut;
+use log::{info, warn};
 
-pub trait EndState {}
+use crate::auth::AuthResp;
+use crate::client::host;
+use crate::codec::{encode_cmd, write_magic};
+use crate::compression::{Compression, Deflate};
+use crate::config::{Config, NsqdConfig};
+use crate::error::ConnError;
+use crate::msgs::{self, NsqCmd, VERSION};
 
-pub trait Transition<S>
-where
-    S: State,
-    Self: State,
-{
-    fn to(self, recv: &dyn AsyncRead, send: &dyn AsyncWrite) -> S;
+/// State waiting for the response of nsqd to the last command written.
+pub trait State {
+    fn on_response(self, resp: &str, buf: &mut BytesMut) -> Result<Handshake, ConnError>;
 }
 
-pub trait Terminate
-where
-    Self: EndState,
-{
-    fn end(self);
+/// What the caller has to do next.
+pub enum Handshake {
+    /// Read the response of nsqd and give it to the waiting state.
+    Waiting(Waiting),
+    /// Upgrade the stream to TLS.
+    Tls(Tls),
+    /// Compress the stream.
+    Compress(Compress),
+    /// Send the first RDY count.
+    Rdy(Rdy),
+    /// Ready to publish or receive messages.
+    Done(Done),
 }
 
-pub struct Magic;
+pub enum Waiting {
+    Identify(Identify),
+    Tls(TlsResponse),
+    Compress(CompressResponse),
+    Auth(Auth),
+    Subscribe(Subscribe),
+}
+
+impl State for Waiting {
+    fn on_response(self, resp: &str, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        match self {
+            Waiting::Identify(s) => s.on_response(resp, buf),
+            Waiting::Tls(s) => s.on_response(resp, buf),
+            Waiting::Compress(s) => s.on_response(resp, buf),
+            Waiting::Auth(s) => s.on_response(resp, buf),
+            Waiting::Subscribe(s) => s.on_response(resp, buf),
+        }
+    }
+}
+
+/// Connection parameters sent to nsqd.
+#[derive(Clone, Debug)]
+pub struct Params {
+    pub addr: String,
+    pub config: Config,
+    pub topic: String,
+    pub channel: String,
+    pub secret: Option<String>,
+}
+
+impl Params {
+    // producers only publish, they don't subscribe.
+    fn subscribe(&self) -> bool {
+        !self.topic.is_empty() || !self.channel.is_empty()
+    }
+}
+
+// what has been agreed with nsqd so far.
+struct Negotiation {
+    params: Params,
+    nsqd: NsqdConfig,
+    auth: Option<AuthResp>,
+}
+
+impl Negotiation {
+    fn write<C: NsqCmd>(&self, buf: &mut BytesMut, cmd: C) {
+        encode_cmd(buf, cmd.as_cmd());
+    }
+
+    // the states after IDENTIFY, each one skipped if nsqd didn't ask for it.
+    fn tls(self, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        if self.nsqd.tls_v1 {
+            return Ok(Handshake::Tls(Tls(self)));
+        }
+        self.compress(buf)
+    }
+
+    fn compress(self, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        if self.nsqd.snappy || self.nsqd.deflate {
+            return Ok(Handshake::Compress(Compress(self)));
+        }
+        self.auth(buf)
+    }
+
+    fn auth(self, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        if !self.nsqd.auth_required {
+            return self.subscribe(buf);
+        }
+        let secret = self.params.secret.clone().ok_or(ConnError::AuthRequired)?;
+        self.write(buf, msgs::Auth(secret));
+        Ok(Handshake::Waiting(Waiting::Auth(Auth(self))))
+    }
+
+    fn subscribe(self, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        if !self.params.subscribe() {
+            return Ok(Handshake::Done(Done(self)));
+        }
+        let sub = msgs::Subscribe(self.params.topic.clone(), self.params.channel.clone());
+        self.write(buf, sub);
+        Ok(Handshake::Waiting(Waiting::Subscribe(Subscribe(self))))
+    }
+}
+
+/// Start of the handshake.
+pub struct Magic(Params);
 
 impl Magic {
-    pub fn new() -> Self {
-        Magic {}
+    pub fn new(params: Params) -> Self {
+        Magic(params)
+    }
+
+    /// Write the protocol version and IDENTIFY.
+    pub fn identify(self, buf: &mut BytesMut) -> Result<Identify, ConnError> {
+        write_magic(buf, VERSION);
+        let config = serde_json::to_string(&self.0.config)?;
+        encode_cmd(buf, msgs::Identify(config).as_cmd());
+        Ok(Identify(self.0))
+    }
+}
+
+pub struct Identify(Params);
+
+impl State for Identify {
+    fn on_response(self, resp: &str, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        let nsqd: NsqdConfig = serde_json::from_str(resp)?;
+        info!("[{}] configuration: {:#?}", self.0.addr, nsqd);
+        let negotiation = Negotiation {
+            params: self.0,
+            nsqd,
+            auth: None,
+        };
+        negotiation.tls(buf)
+    }
+}
+
+/// nsqd waits for the TLS handshake right after the IDENTIFY response.
+pub struct Tls(Negotiation);
+
+impl Tls {
+    /// Name the server certificate is checked against.
+    pub fn server_name(&self) -> String {
+        let params = &self.0.params;
+        match &params.config.tls_server_name {
+            Some(name) => name.clone(),
+            None => host(&params.addr).to_owned(),
+        }
+    }
+
+    /// The stream is upgraded, nsqd confirms over TLS.
+    pub fn upgraded(self) -> Waiting {
+        Waiting::Tls(TlsResponse(self.0))
+    }
+}
+
+pub struct TlsResponse(Negotiation);
+
+impl State for TlsResponse {
+    fn on_response(self, resp: &str, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        info!("[{}] tls connection: {}", self.0.params.addr, resp);
+        self.0.compress(buf)
     }
 }
 
-impl State for Magic {}
+/// nsqd compresses right after its last response, the bytes read past it are compressed.
+pub struct Compress(Negotiation);
 
-impl Transition<Identify> for Magic {
-    fn to(self, recv: &dyn AsyncRead, send: &dyn AsyncWrite) -> Identify {
-        Identify::new(false, false)
+impl Compress {
+    /// Compression to enable on the stream before reading the response.
+    pub fn enable(self) -> (Compression, Waiting) {
+        let nsqd = &self.0.nsqd;
+        let compression = if nsqd.snappy {
+            Compression::Snappy(Box::default())
+        } else {
+            let level = self
+                .0
+                .params
+                .config
+                .deflate_level
+                .min(nsqd.max_deflate_level)
+                .max(1);
+            Compression::Deflate(Deflate::new(u32::from(level)))
+        };
+        (compression, Waiting::Compress(CompressResponse(self.0)))
     }
 }
 
-pub struct Identify {
-    tls: bool,
-    auth: bool,
+pub struct CompressResponse(Negotiation);
+
+impl State for CompressResponse {
+    fn on_response(self, resp: &str, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        info!("[{}] compression: {}", self.0.params.addr, resp);
+        self.0.auth(buf)
+    }
 }
 
-impl Identify {
-    pub fn new(tls: bool, auth: bool) -> Self {
-        Identify {
-            tls: false,
-            auth: false,
+pub struct Auth(Negotiation);
+
+impl State for Auth {
+    fn on_response(mut self, resp: &str, buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        let auth: AuthResp = serde_json::from_str(resp)?;
+        let addr = &self.0.params.addr;
+        info!(
+            "[{}] authenticated as {} ({} permissions)",
+            addr, auth.identity, auth.permission_count
+        );
+        if auth.permission_count == 0 {
+            warn!("[{}] identity {} has no permissions", addr, auth.identity);
         }
+        self.0.auth = Some(auth);
+        self.0.subscribe(buf)
+    }
+}
+
+pub struct Subscribe(Negotiation);
+
+impl State for Subscribe {
+    fn on_response(self, resp: &str, _buf: &mut BytesMut) -> Result<Handshake, ConnError> {
+        let params = &self.0.params;
+        info!(
+            "[{}] subscribe channel: {} topic: {} {}",
+            params.addr, params.channel, params.topic, resp
+        );
+        Ok(Handshake::Rdy(Rdy(self.0)))
+    }
+}
+
+pub struct Rdy(Negotiation);
+
+impl Rdy {
+    /// Write the first RDY, capped to nsqd max_rdy_count.
+    pub fn rdy(self, count: u32, buf: &mut BytesMut) -> Done {
+        self.0
+            .write(buf, msgs::Rdy(count.min(self.0.nsqd.max_rdy_count)));
+        Done(self.0)
     }
+}
 
-    pub fn tls(&self) -> bool {
-        self.tls
+pub struct Done(Negotiation);
+
+impl Done {
+    /// Configuration nsqd answered to IDENTIFY.
+    pub fn nsqd_config(&self) -> &NsqdConfig {
+        &self.0.nsqd
     }
 
-    pub fn auth(&self) -> bool {
-        self.auth
+    /// Identity given by the AUTH response, if nsqd required it.
+    pub fn auth(&self) -> Option<&AuthResp> {
+        self.0.auth.as_ref()
     }
 }
 
-impl State for Identify {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::NSQD_CONFIG;
+
+    fn params(topic: &str, secret: Option<&str>) -> Params {
+        Params {
+            addr: "127.0.0.1:4150".to_owned(),
+            config: Config::default(),
+            topic: topic.to_owned(),
+            channel: topic.to_owned(),
+            secret: secret.map(str::to_owned),
+        }
+    }
+
+    fn nsqd(auth_required: bool) -> String {
+        NSQD_CONFIG.replace(
+            r#""auth_required":false"#,
+            &format!(r#""auth_required":{}"#, auth_required),
+        )
+    }
+
+    fn waiting(handshake: Handshake) -> Waiting {
+        match handshake {
+            Handshake::Waiting(waiting) => waiting,
+            _ => panic!("expected a response to wait for"),
+        }
+    }
+
+    #[test]
+    fn consumer_handshake() {
+        let mut buf = BytesMut::new();
+        let identify = Magic::new(params("t", Some("secret")))
+            .identify(&mut buf)
+            .unwrap();
+        assert!(buf.starts_with(b"  V2IDENTIFY\n"));
+        buf.clear();
+
+        let auth = waiting(identify.on_response(&nsqd(true), &mut buf).unwrap());
+        assert_eq!(&buf[..], &b"AUTH\n\x00\x00\x00\x06secret"[..]);
+        buf.clear();
+
+        let resp = r#"{"identity":"client","permission_count":1}"#;
+        let sub = waiting(auth.on_response(resp, &mut buf).unwrap());
+        assert_eq!(&buf[..], &b"SUB t t\n"[..]);
+        buf.clear();
+
+        let rdy = match sub.on_response("OK", &mut buf).unwrap() {
+            Handshake::Rdy(rdy) => rdy,
+            _ => panic!("expected rdy"),
+        };
+        let done = rdy.rdy(5000, &mut buf);
+        assert_eq!(&buf[..], &b"RDY 2500\n"[..]);
+        assert_eq!(done.auth().unwrap().identity, "client");
+    }
+
+    #[test]
+    fn producer_handshake() {
+        let mut buf = BytesMut::new();
+        let identify = Magic::new(params("", None)).identify(&mut buf).unwrap();
+        buf.clear();
+        match identify.on_response(&nsqd(false), &mut buf).unwrap() {
+            Handshake::Done(done) => assert!(done.auth().is_none()),
+            _ => panic!("producers don't subscribe"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn auth_without_secret() {
+        let mut buf = BytesMut::new();
+        let identify = Magic::new(params("t", None)).identify(&mut buf).unwrap();
+        match identify.on_response(&nsqd(true), &mut buf) {
+            Err(ConnError::AuthRequired) => {}
+            _ => panic!("expected AuthRequired"),
+        }
+    }
+}
@@ -1,157 +1,528 @@
+// without a backend no stream can be built, connect only returns an error.
+#![cfg_attr(
+    not(any(feature = "native-tls", feature = "rustls")),
+    allow(dead_code, unused_variables)
+)]
+
+use crate::config::{Config, TlsBackend};
+use crate::error::ConnError;
+use mio::net::TcpStream;
+use std::io::{self, Read, Write};
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 use crate::config::VerifyServerCert;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use std::fs;
+
+#[cfg(feature = "rustls")]
 use rustls::{
-    Certificate, ClientConfig, ClientSession, RootCertStore, ServerCertVerified,
-    ServerCertVerifier, TLSError,
+    client::{
+        verify_server_cert_signed_by_trust_anchor, verify_server_name, ServerCertVerified,
+        ServerCertVerifier, WebPkiVerifier,
+    },
+    server::ParsedCertificate,
+    Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    ServerName, StreamOwned,
 };
-use std::fs;
-use std::io::BufReader;
-use std::sync::Arc;
+#[cfg(feature = "rustls")]
+use rustls_pemfile::Item;
+#[cfg(feature = "rustls")]
+use std::{convert::TryFrom, io::BufReader, net::Shutdown, sync::Arc, time::SystemTime};
 
-use log::{debug, warn};
-use untrusted;
-use webpki;
-use webpki_roots::TLS_SERVER_ROOTS;
+#[cfg(feature = "rustls")]
+use log::debug;
 
+#[cfg(feature = "rustls")]
 struct NoCertVerification;
 
+#[cfg(feature = "rustls")]
 impl ServerCertVerifier for NoCertVerification {
     fn verify_server_cert(
         &self,
-        _roots: &RootCertStore,
-        _presented_certs: &[Certificate],
-        _dns_name: webpki::DNSNameRef<'_>,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
         _ocsp_response: &[u8],
-    ) -> Result<ServerCertVerified, TLSError> {
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
         Ok(ServerCertVerified::assertion())
     }
 }
 
-type SignatureAlgorithms = &'static [&'static webpki::SignatureAlgorithm];
-
-static SUPPORTED_SIG_ALGS: SignatureAlgorithms = &[
-    &webpki::ECDSA_P256_SHA256,
-    &webpki::ECDSA_P256_SHA384,
-    &webpki::ECDSA_P384_SHA256,
-    &webpki::ECDSA_P384_SHA384,
-    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
-    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
-    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
-    &webpki::RSA_PKCS1_2048_8192_SHA256,
-    &webpki::RSA_PKCS1_2048_8192_SHA384,
-    &webpki::RSA_PKCS1_2048_8192_SHA512,
-    &webpki::RSA_PKCS1_3072_8192_SHA384,
-];
-
+// Verify the chain against a private CA and the name against tls_server_name,
+// or the host of the nsqd address.
+#[cfg(feature = "rustls")]
 struct PrivateVerification {
-    pub time: fn() -> Result<webpki::Time, TLSError>,
+    roots: RootCertStore,
 }
 
+#[cfg(feature = "rustls")]
 impl ServerCertVerifier for PrivateVerification {
     fn verify_server_cert(
         &self,
-        roots: &RootCertStore,
-        presented_certs: &[Certificate],
-        dns_name: webpki::DNSNameRef,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
         _ocsp_response: &[u8],
-    ) -> Result<ServerCertVerified, TLSError> {
-        let (cert, chain, trustroots) = prepare(roots, presented_certs)?;
-        //debug!("cert: {:?}", cert);
-        debug!("chain: {:?}", chain);
-        debug!("roots: {:?}", trustroots);
-        let now = (self.time)()?;
-        let res_cert = cert.verify_is_valid_tls_server_cert(
-            SUPPORTED_SIG_ALGS,
-            &webpki::TLSServerTrustAnchors(&trustroots),
-            &chain,
-            now,
-        );
-        if res_cert.is_err() {
-            warn!("cert: {:?}", res_cert);
-        } else {
-            debug!("cert: {:?}", res_cert);
-        }
-        let res_dns_name = cert.verify_is_valid_for_dns_name(dns_name);
-        if res_dns_name.is_err() {
-            warn!("dns_name: {:?}", res_dns_name);
-        }
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        verify_server_cert_signed_by_trust_anchor(&cert, &self.roots, intermediates, now)?;
+        verify_server_name(&cert, server_name)?;
         Ok(ServerCertVerified::assertion())
     }
 }
 
-impl PrivateVerification {
-    fn new() -> PrivateVerification {
-        PrivateVerification { time: try_now }
+#[cfg(feature = "rustls")]
+fn private_roots(path: &str) -> Result<RootCertStore, ConnError> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(&certs);
+    debug!(
+        "private CA {}: {} certificates added, {} ignored",
+        path, added, ignored
+    );
+    if added == 0 {
+        return Err(ConnError::Error(format!(
+            "no CA certificate found in {}",
+            path
+        )));
     }
+    Ok(roots)
 }
 
-fn try_now() -> Result<webpki::Time, TLSError> {
-    webpki::Time::try_from(std::time::SystemTime::now())
-        .map_err(|_| TLSError::FailedToGetCurrentTime)
+#[cfg(feature = "rustls")]
+fn client_identity(cert: &str, key: &str) -> Result<(Vec<Certificate>, PrivateKey), ConnError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(cert)?))?;
+    let mut reader = BufReader::new(fs::File::open(key)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok((
+                    certs.into_iter().map(Certificate).collect(),
+                    PrivateKey(key),
+                ));
+            }
+            _ => continue,
+        }
+    }
+    Err(ConnError::Error(format!("no private key found in {}", key)))
 }
 
-fn prepare<'a, 'b>(
-    roots: &'b RootCertStore,
-    presented_certs: &'a [Certificate],
-) -> Result<
-    (
-        webpki::EndEntityCert<'a>,
-        Vec<untrusted::Input<'a>>,
-        Vec<webpki::TrustAnchor<'b>>,
-    ),
-    TLSError,
-> {
-    if presented_certs.is_empty() {
-        return Err(TLSError::NoCertificatesPresented);
+#[cfg(feature = "rustls")]
+pub(crate) fn rustls_config(config: &Config) -> Result<ClientConfig, ConnError> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let verifier: Arc<dyn ServerCertVerifier> = match &config.verify_server_cert {
+        VerifyServerCert::None => Arc::new(NoCertVerification),
+        VerifyServerCert::PrivateCA(path) => Arc::new(PrivateVerification {
+            roots: private_roots(path)?,
+        }),
+        VerifyServerCert::PublicCA => {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            Arc::new(WebPkiVerifier::new(roots, None))
+        }
+    };
+    let builder = builder.with_custom_certificate_verifier(verifier);
+    match (&config.tls_client_cert, &config.tls_client_key) {
+        (Some(cert), Some(key)) => {
+            let (certs, key) = client_identity(cert, key)?;
+            Ok(builder.with_client_auth_cert(certs, key)?)
+        }
+        _ => Ok(builder.with_no_client_auth()),
     }
+}
+
+/// TLS stream over the nsqd socket, whatever backend established it.
+pub enum TlsStream {
+    #[cfg(feature = "native-tls")]
+    Native(native_tls::TlsStream<TcpStream>),
+    #[cfg(feature = "rustls")]
+    Rustls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
 
-    // EE cert must appear first.
-    let cert_der = untrusted::Input::from(&presented_certs[0].0);
-    let cert = webpki::EndEntityCert::from(cert_der).map_err(TLSError::WebPKIError)?;
+/// Progress of a TLS handshake on a non-blocking socket.
+pub enum Handshake {
+    Done(TlsStream),
+    /// The socket would block, resume it with [MidHandshake::handshake] once it is ready.
+    InProgress(MidHandshake),
+}
 
-    let chain: Vec<untrusted::Input> = presented_certs
-        .iter()
-        .skip(1)
-        .map(|cert| untrusted::Input::from(&cert.0))
-        .collect();
+pub enum MidHandshake {
+    #[cfg(feature = "native-tls")]
+    Native(native_tls::MidHandshakeTlsStream<TcpStream>),
+    #[cfg(feature = "rustls")]
+    Rustls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
 
-    let trustroots: Vec<webpki::TrustAnchor> =
-        roots.roots.iter().map(|x| x.to_trust_anchor()).collect();
+impl MidHandshake {
+    pub fn get_ref(&self) -> &TcpStream {
+        match *self {
+            #[cfg(feature = "native-tls")]
+            MidHandshake::Native(ref s) => s.get_ref(),
+            #[cfg(feature = "rustls")]
+            MidHandshake::Rustls(ref s) => s.get_ref(),
+        }
+    }
 
-    Ok((cert, chain, trustroots))
+    /// Go on with the handshake after a readiness event on the socket.
+    pub fn handshake(self) -> Result<Handshake, ConnError> {
+        match self {
+            #[cfg(feature = "native-tls")]
+            MidHandshake::Native(s) => native_handshake(s.handshake()),
+            #[cfg(feature = "rustls")]
+            MidHandshake::Rustls(s) => rustls_handshake(s),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct TlsSession(pub ClientSession);
+impl TlsStream {
+    /// Start the handshake on the socket, it goes as far as the socket allows without blocking.
+    pub fn connect(
+        config: &Config,
+        server_name: &str,
+        socket: TcpStream,
+    ) -> Result<Handshake, ConnError> {
+        match config.tls_backend {
+            TlsBackend::NativeTls => native_connect(config, server_name, socket),
+            TlsBackend::Rustls => rustls_connect(config, server_name, socket),
+        }
+    }
 
-impl TlsSession {
-    pub fn new<S: Into<String> + Clone>(
-        hostname: &str,
-        verify_server_cert: VerifyServerCert<S>,
-    ) -> TlsSession {
-        let dns_name = webpki::DNSNameRef::try_from_ascii_str(hostname).unwrap();
-        let mut config = ClientConfig::new();
-        match verify_server_cert {
-            VerifyServerCert::None => {
-                config
-                    .dangerous()
-                    .set_certificate_verifier(Arc::new(NoCertVerification));
+    pub fn get_ref(&self) -> &TcpStream {
+        match *self {
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native(ref s) => s.get_ref(),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(ref s) => s.get_ref(),
+        }
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match *self {
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native(ref mut s) => s.shutdown(),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(ref mut s) => {
+                s.conn.send_close_notify();
+                let _ = s.flush();
+                s.sock.shutdown(Shutdown::Both)
             }
-            VerifyServerCert::PrivateCA(s) => {
-                let private_ca_root_path = s.into();
-                let certfile = fs::File::open(&private_ca_root_path).expect("cannot open CA file");
-                let mut reader = BufReader::new(certfile);
-                config.root_store.add_pem_file(&mut reader).unwrap();
-                config
-                    .dangerous()
-                    .set_certificate_verifier(Arc::new(PrivateVerification::new()));
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native(ref mut s) => s.read(buf),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native(ref mut s) => s.write(buf),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native(ref mut s) => s.flush(),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(feature = "native-tls")]
+fn native_connect(
+    config: &Config,
+    server_name: &str,
+    socket: TcpStream,
+) -> Result<Handshake, ConnError> {
+    let connector = native_connector(config)?;
+    native_handshake(connector.connect(server_name, socket))
+}
+
+#[cfg(feature = "native-tls")]
+pub(crate) fn native_connector(config: &Config) -> Result<native_tls::TlsConnector, ConnError> {
+    use native_tls::{Identity, TlsConnector};
+    let mut builder = TlsConnector::builder();
+    match &config.verify_server_cert {
+        VerifyServerCert::None => {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        VerifyServerCert::PrivateCA(path) => {
+            let ca = native_tls::Certificate::from_pem(&fs::read(path)?)?;
+            builder
+                .add_root_certificate(ca)
+                .disable_built_in_roots(true);
+        }
+        VerifyServerCert::PublicCA => {}
+    }
+    if let (Some(cert), Some(key)) = (&config.tls_client_cert, &config.tls_client_key) {
+        builder.identity(Identity::from_pkcs8(&fs::read(cert)?, &fs::read(key)?)?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(feature = "native-tls")]
+fn native_handshake(
+    res: Result<native_tls::TlsStream<TcpStream>, native_tls::HandshakeError<TcpStream>>,
+) -> Result<Handshake, ConnError> {
+    use native_tls::HandshakeError;
+    match res {
+        Ok(s) => Ok(Handshake::Done(TlsStream::Native(s))),
+        Err(HandshakeError::Failure(e)) => Err(e.into()),
+        Err(HandshakeError::WouldBlock(s)) => Ok(Handshake::InProgress(MidHandshake::Native(s))),
+    }
+}
+
+#[cfg(not(feature = "native-tls"))]
+fn native_connect(
+    _config: &Config,
+    _server_name: &str,
+    _socket: TcpStream,
+) -> Result<Handshake, ConnError> {
+    Err(ConnError::Error(
+        "native-tls support not compiled, enable the native-tls feature".to_owned(),
+    ))
+}
+
+#[cfg(feature = "rustls")]
+fn rustls_connect(
+    config: &Config,
+    server_name: &str,
+    socket: TcpStream,
+) -> Result<Handshake, ConnError> {
+    let tls_config = rustls_config(config)?;
+    let name = ServerName::try_from(server_name)
+        .map_err(|e| ConnError::Error(format!("invalid tls server name {}: {}", server_name, e)))?;
+    let conn = ClientConnection::new(Arc::new(tls_config), name)?;
+    rustls_handshake(Box::new(StreamOwned::new(conn, socket)))
+}
+
+#[cfg(feature = "rustls")]
+fn rustls_handshake(
+    mut stream: Box<StreamOwned<ClientConnection, TcpStream>>,
+) -> Result<Handshake, ConnError> {
+    while stream.conn.is_handshaking() {
+        let StreamOwned { conn, sock } = &mut *stream;
+        match conn.complete_io(sock) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Handshake::InProgress(MidHandshake::Rustls(stream)));
             }
-            VerifyServerCert::PublicCA => {
-                config
-                    .root_store
-                    .add_server_trust_anchors(&TLS_SERVER_ROOTS);
+            Err(e) => return Err(rustls_error(e)),
+        }
+    }
+    Ok(Handshake::Done(TlsStream::Rustls(stream)))
+}
+
+// rustls reports handshake failures as io errors wrapping its own.
+#[cfg(feature = "rustls")]
+pub(crate) fn rustls_error(e: io::Error) -> ConnError {
+    let is_tls = e.get_ref().is_some_and(|inner| inner.is::<rustls::Error>());
+    if !is_tls {
+        return ConnError::IoError(e);
+    }
+    match e.into_inner() {
+        Some(inner) => ConnError::TlsError(inner),
+        None => ConnError::Error("tls handshake failed".to_owned()),
+    }
+}
+
+#[cfg(not(feature = "rustls"))]
+fn rustls_connect(
+    _config: &Config,
+    _server_name: &str,
+    _socket: TcpStream,
+) -> Result<Handshake, ConnError> {
+    Err(ConnError::Error(
+        "rustls support not compiled, enable the rustls feature".to_owned(),
+    ))
+}
+
+// the fake nsqd serves tls through native-tls.
+#[cfg(all(test, feature = "native-tls"))]
+mod tests {
+    use super::*;
+    use native_tls::{Identity, TlsAcceptor};
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const CERTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+
+    fn backends() -> Vec<TlsBackend> {
+        let mut backends = vec![TlsBackend::NativeTls];
+        if cfg!(feature = "rustls") {
+            backends.push(TlsBackend::Rustls);
+        }
+        backends
+    }
+
+    // handshake with the server accepting the connection, returns the client side result.
+    fn connect<F>(config: &Config, server_name: &str, accept: F) -> Result<(), ConnError>
+    where
+        F: FnOnce(std::net::TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || accept(listener.accept().unwrap().0));
+        let socket = TcpStream::connect(&addr).unwrap();
+        let mut handshake = TlsStream::connect(config, server_name, socket);
+        let res = loop {
+            match handshake {
+                Ok(Handshake::Done(_)) => break Ok(()),
+                Ok(Handshake::InProgress(mid)) => {
+                    thread::sleep(Duration::from_millis(1));
+                    handshake = mid.handshake();
+                }
+                Err(e) => break Err(e),
             }
         };
-        let sess = ClientSession::new(&Arc::new(config), dns_name);
-        TlsSession(sess)
+        server.join().unwrap();
+        res
+    }
+
+    // handshake with a server presenting a certificate for localhost signed by the test CA.
+    fn handshake(config: &Config, server_name: &str) -> Result<(), ConnError> {
+        let cert = fs::read(format!("{}/server.pem", CERTS)).unwrap();
+        let key = fs::read(format!("{}/server.key", CERTS)).unwrap();
+        let acceptor = TlsAcceptor::new(Identity::from_pkcs8(&cert, &key).unwrap()).unwrap();
+        connect(config, server_name, move |stream| {
+            // fails when the client refuses the certificate.
+            let _ = acceptor.accept(stream);
+        })
+    }
+
+    // handshake with a server asking for a client certificate signed by the test CA,
+    // returns whether the server accepted the client.
+    fn client_auth(config: &Config) -> bool {
+        use rustls::server::AllowAnyAuthenticatedClient;
+        use rustls::{ServerConfig, ServerConnection};
+
+        let pem =
+            |name: &str| BufReader::new(fs::File::open(format!("{}/{}", CERTS, name)).unwrap());
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut pem("ca.pem")).unwrap() {
+            roots.add(&rustls::Certificate(cert)).unwrap();
+        }
+        let certs = rustls_pemfile::certs(&mut pem("server.pem")).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut pem("server.key")).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            .with_single_cert(
+                certs.into_iter().map(rustls::Certificate).collect(),
+                rustls::PrivateKey(key[0].clone()),
+            )
+            .unwrap();
+        let (accepted_s, accepted_r) = std::sync::mpsc::channel();
+        let res = connect(config, "localhost", move |mut stream| {
+            let mut conn = ServerConnection::new(Arc::new(server_config)).unwrap();
+            let mut accepted = true;
+            while conn.is_handshaking() {
+                if conn.complete_io(&mut stream).is_err() {
+                    accepted = false;
+                    break;
+                }
+            }
+            accepted_s.send(accepted).unwrap();
+        });
+        let accepted = accepted_r.recv().unwrap();
+        // with tls 1.3 the client is done before the server checked its certificate.
+        assert!(res.is_ok() || !accepted);
+        accepted
+    }
+
+    #[test]
+    fn private_ca() {
+        for backend in backends() {
+            let ca = VerifyServerCert::PrivateCA(format!("{}/ca.pem", CERTS));
+            let config = Config::new().tls_backend(backend).verify_server_cert(ca);
+            assert!(handshake(&config, "localhost").is_ok(), "{:?}", backend);
+            // signed by the CA but for another name.
+            assert!(handshake(&config, "nsqd.invalid").is_err(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn public_ca() {
+        for backend in backends() {
+            let config = Config::new().tls_backend(backend);
+            assert!(handshake(&config, "localhost").is_err(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn no_verification() {
+        for backend in backends() {
+            let config = Config::new()
+                .tls_backend(backend)
+                .verify_server_cert(VerifyServerCert::None);
+            assert!(handshake(&config, "nsqd.invalid").is_ok(), "{:?}", backend);
+        }
+    }
+
+    fn with_client_cert(backend: TlsBackend, cert: &str, key: &str) -> Config {
+        let ca = VerifyServerCert::PrivateCA(format!("{}/ca.pem", CERTS));
+        Config::new()
+            .tls_backend(backend)
+            .verify_server_cert(ca)
+            .tls_client_cert(
+                &format!("{}/{}", CERTS, cert),
+                &format!("{}/{}", CERTS, key),
+            )
+    }
+
+    #[test]
+    fn client_cert() {
+        for backend in backends() {
+            let config = with_client_cert(backend, "client.pem", "client.key");
+            assert!(client_auth(&config), "{:?}", backend);
+            // without one the server refuses the client.
+            let ca = VerifyServerCert::PrivateCA(format!("{}/ca.pem", CERTS));
+            let config = Config::new().tls_backend(backend).verify_server_cert(ca);
+            assert!(!client_auth(&config), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn client_key_errors() {
+        for backend in backends() {
+            for key in ["missing.key", "client.pem"].iter() {
+                let config = with_client_cert(backend, "client.pem", key);
+                let socket = TcpStream::connect(&"127.0.0.1:1".parse().unwrap());
+                // the key is loaded before the socket is used.
+                let res = socket.map_err(ConnError::from).and_then(|socket| {
+                    TlsStream::connect(&config, "localhost", socket).map(|_| ())
+                });
+                assert!(res.is_err(), "{:?} {}", backend, key);
+            }
+        }
     }
 }